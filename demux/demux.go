@@ -2,29 +2,42 @@ package demux
 
 import (
 	"bufio"
+	"bytes"
 	"encoding/binary"
+	"fmt"
 	"io"
 	"log"
 	"os"
 	"ubvremux/ubv"
 )
 
-func DemuxSinglePartitionToNewFiles(ubvFilename string, videoFilename string, audioFilename string, partition *ubv.UbvPartition) {
+// Opens a .ubv for demuxing. The handle can be shared across all the partitions of the file
+func OpenUbv(ubvFilename string) (*os.File, error) {
+	return os.OpenFile(ubvFilename, os.O_RDONLY, 0)
+}
 
-	// The input media file; N.B. we do not use a buffered reader for this because we will be seeking heavily
-	ubvFile, err := os.OpenFile(ubvFilename, os.O_RDONLY, 0)
-	if err != nil {
-		log.Fatal("Error opening UBV file", err)
-	}
+// Demuxes a partition to new raw bitstream files, the video being of videoCodec ("h264" or "hevc"). The frame buffer is
+// reused if large enough, and returned (possibly grown) so it can be passed to subsequent calls
+func DemuxSinglePartitionToNewFiles(ubvFile *os.File, videoFilename string, audioFilename string, videoCodec string, partition *ubv.UbvPartition, buffer []byte) ([]byte, error) {
+	return DemuxPartitionsToNewFiles([]*os.File{ubvFile}, []*ubv.UbvPartition{partition}, videoFilename, audioFilename, videoCodec, buffer)
+}
 
-	defer ubvFile.Close()
+// Demuxes several partitions, one after another, to the same new raw bitstream files (e.g. to join the partitions
+// of consecutive .ubv files into one output). Each partition is read from the .ubv at the same index of ubvFiles
+func DemuxPartitionsToNewFiles(ubvFiles []*os.File, partitions []*ubv.UbvPartition, videoFilename string, audioFilename string, videoCodec string, buffer []byte) ([]byte, error) {
+	var videoTrackCount int
+	var audioTrackCount int
+	for _, partition := range partitions {
+		videoTrackCount += partition.VideoTrackCount
+		audioTrackCount += partition.AudioTrackCount
+	}
 
 	// Optionally write video
 	var videoFile *bufio.Writer
-	if len(videoFilename) > 0 && partition.VideoTrackCount > 0 {
+	if len(videoFilename) > 0 && videoTrackCount > 0 {
 		videoFileRaw, err := os.Create(videoFilename)
 		if err != nil {
-			log.Fatal("Error opening video bitstream output", err)
+			return buffer, fmt.Errorf("error opening video bitstream output: %v", err)
 		}
 
 		defer videoFileRaw.Close()
@@ -35,10 +48,10 @@ func DemuxSinglePartitionToNewFiles(ubvFilename string, videoFilename string, au
 
 	// Optionally write audio
 	var audioFile *bufio.Writer
-	if len(audioFilename) > 0 && partition.AudioTrackCount > 0 {
+	if len(audioFilename) > 0 && audioTrackCount > 0 {
 		audioFileRaw, err := os.Create(audioFilename)
 		if err != nil {
-			log.Fatal("Error opening audio bitstream output", err)
+			return buffer, fmt.Errorf("error opening audio bitstream output: %v", err)
 		}
 
 		defer audioFileRaw.Close()
@@ -48,13 +61,23 @@ func DemuxSinglePartitionToNewFiles(ubvFilename string, videoFilename string, au
 		audioFile = nil
 	}
 
-	DemuxSinglePartition(ubvFilename, partition, videoFile, ubvFile, audioFile)
+	for i, partition := range partitions {
+		var err error
+		if buffer, err = DemuxSinglePartition(ubvFiles[i].Name(), partition, videoFile, ubvFiles[i], audioFile, videoCodec, buffer); err != nil {
+			return buffer, err
+		}
+	}
+
+	return buffer, nil
 }
 
 // Extract video and audio data from a given partition of a .ubv file into raw .H264 bitstream and/or raw .AAC bitstream file
-func DemuxSinglePartition(ubvFilename string, partition *ubv.UbvPartition, videoFile *bufio.Writer, ubvFile *os.File, audioFile *bufio.Writer) {
-	// Allocate a buffer large enough for the largest frame
-	var buffer []byte
+// Returns the frame buffer used, which callers should pass back in for the next partition to avoid reallocating
+// The .ubv may come from any seekable source (ubvFilename is only used in messages); frames are read by offset, so a
+// non-seekable source (e.g. an HTTP response body) must be buffered first, for example to a temporary file
+// The video's NALs are parsed as videoCodec ("h264" or "hevc") to spot changes to its SPS
+func DemuxSinglePartition(ubvFilename string, partition *ubv.UbvPartition, videoFile *bufio.Writer, ubvFile io.ReadSeeker, audioFile *bufio.Writer, videoCodec string, buffer []byte) ([]byte, error) {
+	// Make sure the buffer is large enough for the largest frame (only ever growing it)
 	{
 		bufferSize := 0
 		for _, frame := range partition.Frames {
@@ -62,19 +85,34 @@ func DemuxSinglePartition(ubvFilename string, partition *ubv.UbvPartition, video
 				bufferSize = frame.Size
 			}
 		}
-		buffer = make([]byte, bufferSize)
+
+		if len(buffer) < bufferSize {
+			buffer = make([]byte, bufferSize)
+		}
 	}
 
-	// Write opening NAL separator to video track
-	if videoFile != nil {
-		if bytesWritten, err := videoFile.Write([]byte{0, 0, 0, 1}); err != nil {
-			log.Fatal("Failed to write output NAL Separator! Only wrote ", bytesWritten, ". Error:", err)
-		} else if bytesWritten != 4 {
-			log.Fatal("Tried to write 4 bytes of NAL separator, but wrote ", bytesWritten)
+	// Work out how much essence will be written, for progress reporting
+	var progress *progressReporter
+	{
+		var totalBytes int64
+		for _, frame := range partition.Frames {
+			if (frame.TrackNumber == 7 && videoFile != nil) || (frame.TrackNumber == 1000 && audioFile != nil) {
+				totalBytes += int64(frame.Size)
+			}
 		}
+
+		progress = newProgressReporter(partition.Name(), totalBytes)
 	}
 
-	for _, frame := range partition.Frames {
+	// Frames are mostly laid out in increasing offset order, so read them through a buffer rather than seeking for each
+	reader := newUbvReader(ubvFile)
+
+	// The most recent SPS seen. FFmpeg takes the codec parameters for the whole MP4 from the first, so a change
+	// (e.g. the camera's resolution being changed) mid-partition will garble the output from that point
+	var sps []byte
+	spsChanges := 0
+
+	for i, frame := range partition.Frames {
 		if frame.TrackNumber == 7 && videoFile != nil {
 			// Video packet - contains one or more length-prefixed NALs
 			frameDataRead := 0
@@ -83,65 +121,88 @@ func DemuxSinglePartition(ubvFilename string, partition *ubv.UbvPartition, video
 			//      memory and then working on it as a byte array
 			for frameDataRead < frame.Size {
 				// Seek to H.264 NAL length prefix
-				if _, err := ubvFile.Seek(int64(frame.Offset+frameDataRead), io.SeekStart); err != nil {
-					log.Fatal("Failed to seek to ", int64(frame.Offset+frameDataRead), " in ", ubvFilename, ": ", err)
+				if err := reader.SeekTo(int64(frame.Offset + frameDataRead)); err != nil {
+					return buffer, fmt.Errorf("failed to seek to %d in %s: %v", frame.Offset+frameDataRead, ubvFilename, err)
 				}
 
 				var nalSize int32
-				if err := binary.Read(ubvFile, binary.BigEndian, &nalSize); err != nil {
-					log.Fatal("Failed to read H.264 NAL size from ", ubvFilename, err)
-				} else if frameDataRead+int(nalSize) > frame.Size {
-					// Warn if we would read beyond this Frame
-					log.Fatal("Read goes beyond frame size! pos within frame: ", frameDataRead, " nalSize: ", nalSize, ", frame.Size:", frame.Size)
+				if err := binary.Read(reader, binary.BigEndian, &nalSize); err != nil {
+					return buffer, fmt.Errorf("failed to read H.264 NAL size from %s: %v", ubvFilename, err)
+				} else if nalSize < 0 || frameDataRead+4+int(nalSize) > frame.Size {
+					// Fail rather than read beyond this frame
+					return buffer, fmt.Errorf("NAL of %d bytes at %d goes beyond frame of %d bytes at %d", nalSize, frameDataRead, frame.Size, frame.Offset)
 				}
 
 				frameDataRead += 4
 
 				// Read
-				if _, err := io.ReadFull(ubvFile, buffer[0:nalSize]); err != nil {
-					log.Fatal("Failed to read ", frame.Size, " bytes of video essence at ", frame.Offset, err)
+				if _, err := io.ReadFull(reader, buffer[0:nalSize]); err != nil {
+					return buffer, fmt.Errorf("failed to read %d bytes of video essence at %d: %v", frame.Size, frame.Offset, err)
 				}
 
 				frameDataRead += int(nalSize)
 
-				// Write H.264 essence
-				if bytesWritten, err := videoFile.Write(buffer[0:nalSize]); err != nil {
-					log.Fatal("Failed to write output video data! Only wrote ", bytesWritten, " bytes. Error:", err)
+				if ubv.IsSPS(buffer[0:nalSize], videoCodec) {
+					if sps != nil && !bytes.Equal(sps, buffer[0:nalSize]) {
+						spsChanges++
+						log.Printf("Warning: video SPS changed at frame %d of partition %s (resolution or encoder settings changed?), output after this point may be garbled", i, partition.Name())
+					}
+
+					sps = append(sps[:0], buffer[0:nalSize]...)
 				}
-				// Write NAL separator
-				if bytesWritten, err := videoFile.Write([]byte{0, 0, 0, 1}); err != nil {
-					log.Fatal("Failed to write output NAL Separator! Only wrote ", bytesWritten, " bytes. Error:", err)
+
+				// Write NAL separator, then H.264 essence. N.B. the separator goes before each NAL so that consecutive
+				// partitions can be written to the same bitstream
+				if _, err := videoFile.Write([]byte{0, 0, 0, 1}); err != nil {
+					return buffer, fmt.Errorf("failed to write output NAL separator: %v", err)
+				}
+				if _, err := videoFile.Write(buffer[0:nalSize]); err != nil {
+					return buffer, fmt.Errorf("failed to write output video data: %v", err)
 				}
 			}
 
+			progress.add(int64(frame.Size))
+
 		} else if frame.TrackNumber == 1000 && audioFile != nil {
 			// Audio packet - contains raw AAC bitstream
 
 			// Seek
-			if _, err := ubvFile.Seek(int64(frame.Offset), io.SeekStart); err != nil {
-				log.Fatal("Failed to seek to ", frame.Offset, "in ", ubvFilename, err)
+			if err := reader.SeekTo(int64(frame.Offset)); err != nil {
+				return buffer, fmt.Errorf("failed to seek to %d in %s: %v", frame.Offset, ubvFilename, err)
 			}
 
 			// Read
-			if _, err := io.ReadFull(ubvFile, buffer[0:frame.Size]); err != nil {
-				log.Fatal("Failed to read ", frame.Size, " bytes at ", frame.Offset, err)
+			if _, err := io.ReadFull(reader, buffer[0:frame.Size]); err != nil {
+				return buffer, fmt.Errorf("failed to read %d bytes of audio essence at %d: %v", frame.Size, frame.Offset, err)
 			}
 
-			if bytesWritten, err := audioFile.Write(buffer[0:frame.Size]); err != nil {
-				log.Fatal("Failed to write output audio data! Only wrote ", bytesWritten, ". Error:", err)
+			if _, err := audioFile.Write(buffer[0:frame.Size]); err != nil {
+				return buffer, fmt.Errorf("failed to write output audio data: %v", err)
 			}
+
+			progress.add(int64(frame.Size))
 		} else {
 			continue
 		}
 	}
 
+	if spsChanges > 0 {
+		log.Printf("Warning: video SPS changed %d time(s) within partition %s, the MP4 will only use the first", spsChanges, partition.Name())
+	}
+
 	// Flush all buffered output data
 
 	if audioFile != nil {
-		audioFile.Flush()
+		if err := audioFile.Flush(); err != nil {
+			return buffer, fmt.Errorf("failed to write output audio data: %v", err)
+		}
 	}
 
 	if videoFile != nil {
-		videoFile.Flush()
+		if err := videoFile.Flush(); err != nil {
+			return buffer, fmt.Errorf("failed to write output video data: %v", err)
+		}
 	}
+
+	return buffer, nil
 }
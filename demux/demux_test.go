@@ -0,0 +1,118 @@
+package demux
+
+import (
+	"bufio"
+	"bytes"
+	"encoding/binary"
+	"io"
+	"io/ioutil"
+	"testing"
+	"ubvremux/ubv"
+)
+
+// Counts the real seeks made on the underlying file
+type countingSeeker struct {
+	io.ReadSeeker
+	seeks int
+}
+
+func (s *countingSeeker) Seek(offset int64, whence int) (int64, error) {
+	s.seeks++
+
+	return s.ReadSeeker.Seek(offset, whence)
+}
+
+func TestUbvReaderSeekTo(t *testing.T) {
+	data := make([]byte, 3*MAX_SEQUENTIAL_SKIP)
+	for i := range data {
+		data[i] = byte(i % 251)
+	}
+
+	type read struct {
+		offset int64
+		length int
+	}
+
+	cases := []struct {
+		name  string
+		reads []read
+		seeks int
+	}{
+		{"forward small", []read{{0, 10}, {100, 10}, {5000, 100}, {MAX_SEQUENTIAL_SKIP, 10}}, 1},
+		{"forward large", []read{{0, 10}, {MAX_SEQUENTIAL_SKIP + 100, 10}, {2*MAX_SEQUENTIAL_SKIP + 200, 10}}, 3},
+		{"backward", []read{{2 * MAX_SEQUENTIAL_SKIP, 10}, {100, 10}, {99, 1}}, 3},
+		{"same offset", []read{{50, 10}, {60, 10}, {70, 0}, {70, 10}}, 1},
+	}
+
+	for _, c := range cases {
+		file := &countingSeeker{ReadSeeker: bytes.NewReader(data)}
+		reader := newUbvReader(file)
+
+		for _, r := range c.reads {
+			if err := reader.SeekTo(r.offset); err != nil {
+				t.Fatalf("%s: SeekTo(%d) failed: %v", c.name, r.offset, err)
+			}
+
+			actual := make([]byte, r.length)
+			if _, err := io.ReadFull(reader, actual); err != nil {
+				t.Fatalf("%s: read of %d bytes at %d failed: %v", c.name, r.length, r.offset, err)
+			}
+
+			if expected := data[r.offset : r.offset+int64(r.length)]; !bytes.Equal(actual, expected) {
+				t.Errorf("%s: read at %d returned the wrong data", c.name, r.offset)
+			}
+
+			if reader.position != r.offset+int64(r.length) {
+				t.Errorf("%s: after reading %d bytes at %d, position is %d", c.name, r.length, r.offset, reader.position)
+			}
+		}
+
+		if file.seeks != c.seeks {
+			t.Errorf("%s: expected %d seek(s) on the file, got %d", c.name, c.seeks, file.seeks)
+		}
+	}
+}
+
+func BenchmarkDemux(b *testing.B) {
+	const videoFrameSize = 8192
+	const audioFrameSize = 300
+
+	partition := &ubv.UbvPartition{
+		Tracks: map[int]*ubv.UbvTrack{
+			7:    {IsVideo: true, TrackNumber: 7},
+			1000: {TrackNumber: 1000},
+		},
+		VideoTrackCount: 1,
+		AudioTrackCount: 1,
+	}
+
+	// 1000 video frames (each a single NAL) and audio packets, laid out back to back
+	var data bytes.Buffer
+	for i := 0; i < 1000; i++ {
+		nal := make([]byte, videoFrameSize-4)
+		nal[0] = 0x41
+
+		partition.Frames = append(partition.Frames, ubv.UbvFrame{TrackNumber: 7, Offset: data.Len(), Size: videoFrameSize})
+		binary.Write(&data, binary.BigEndian, int32(len(nal)))
+		data.Write(nal)
+
+		partition.Frames = append(partition.Frames, ubv.UbvFrame{TrackNumber: 1000, Offset: data.Len(), Size: audioFrameSize})
+		data.Write(make([]byte, audioFrameSize))
+	}
+
+	partition.FrameCount = len(partition.Frames)
+
+	var buffer []byte
+	video := bufio.NewWriter(ioutil.Discard)
+	audio := bufio.NewWriter(ioutil.Discard)
+
+	b.SetBytes(int64(data.Len()))
+	b.ResetTimer()
+
+	for i := 0; i < b.N; i++ {
+		var err error
+		if buffer, err = DemuxSinglePartition("benchmark.ubv", partition, video, bytes.NewReader(data.Bytes()), audio, "h264", buffer); err != nil {
+			b.Fatal(err)
+		}
+	}
+}
@@ -0,0 +1,47 @@
+package demux
+
+import (
+	"log"
+	"time"
+)
+
+// How often to log demux progress
+const PROGRESS_INTERVAL = 5 * time.Second
+
+// Logs progress through a partition in terms of essence bytes written (frame sizes vary too much between keyframes
+// and other frames for frame counts to give a useful ETA)
+type progressReporter struct {
+	partitionName string
+	totalBytes    int64
+	doneBytes     int64
+	started       time.Time
+	lastReport    time.Time
+}
+
+func newProgressReporter(partitionName string, totalBytes int64) *progressReporter {
+	now := time.Now()
+
+	return &progressReporter{
+		partitionName: partitionName,
+		totalBytes:    totalBytes,
+		started:       now,
+		lastReport:    now,
+	}
+}
+
+// Records that more bytes have been written, logging progress and an ETA if PROGRESS_INTERVAL has elapsed
+func (p *progressReporter) add(bytes int64) {
+	p.doneBytes += bytes
+
+	now := time.Now()
+	if now.Sub(p.lastReport) < PROGRESS_INTERVAL || p.doneBytes <= 0 || p.totalBytes <= 0 {
+		return
+	}
+
+	p.lastReport = now
+
+	elapsed := now.Sub(p.started)
+	remaining := time.Duration(float64(elapsed) * float64(p.totalBytes-p.doneBytes) / float64(p.doneBytes))
+
+	log.Printf("Partition %s: %.1f%% (%d of %d bytes), ETA %s", p.partitionName, 100*float64(p.doneBytes)/float64(p.totalBytes), p.doneBytes, p.totalBytes, remaining.Round(time.Second))
+}
@@ -0,0 +1,60 @@
+package demux
+
+import (
+	"bufio"
+	"io"
+)
+
+const (
+	// Size of the read buffer used when demuxing
+	READ_BUFFER_SIZE = 1024 * 1024
+
+	// Forward jumps of up to this many bytes are read through rather than seeked over
+	MAX_SEQUENTIAL_SKIP = 1024 * 1024
+)
+
+// Reads from a .ubv through a buffer for as long as reads move forwards through the file (which frames within a
+// partition almost always do), falling back to a real seek only when an offset jumps backwards or far ahead
+type ubvReader struct {
+	file   io.ReadSeeker
+	reader *bufio.Reader
+
+	// The absolute offset in file the buffered reader is positioned at, or -1 if unknown
+	position int64
+}
+
+func newUbvReader(file io.ReadSeeker) *ubvReader {
+	return &ubvReader{
+		file:     file,
+		reader:   bufio.NewReaderSize(file, READ_BUFFER_SIZE),
+		position: -1,
+	}
+}
+
+// Positions the reader at an absolute offset within the file
+func (r *ubvReader) SeekTo(offset int64) error {
+	if r.position >= 0 && offset >= r.position && offset-r.position <= MAX_SEQUENTIAL_SKIP {
+		discarded, err := r.reader.Discard(int(offset - r.position))
+		r.position += int64(discarded)
+
+		return err
+	}
+
+	if _, err := r.file.Seek(offset, io.SeekStart); err != nil {
+		r.position = -1
+
+		return err
+	}
+
+	r.reader.Reset(r.file)
+	r.position = offset
+
+	return nil
+}
+
+func (r *ubvReader) Read(p []byte) (int, error) {
+	n, err := r.reader.Read(p)
+	r.position += int64(n)
+
+	return n, err
+}
@@ -1,19 +1,209 @@
 package ffmpegutil
 
 import (
+	"fmt"
 	"log"
 	"os"
 	"os/exec"
 	"strconv"
+	"time"
 	"ubvremux/ubv"
 )
 
-func MuxVideoOnly(partition *ubv.UbvPartition, h264File string, mp4File string) {
+// FFmpeg's own default for -probesize
+const FFMPEG_DEFAULT_PROBESIZE = 5000000
+
+// Appended to the name of an MP4 while FFmpeg is writing it
+const PARTIAL_SUFFIX = ".partial"
+
+// Options applied to the FFmpeg mux invocations
+type MuxOptions struct {
+	// If non-zero, the number of bytes FFmpeg may read from each input to find its codec parameters.
+	// If zero, this is sized to cover the first ubv.PROBE_FRAMES video frames of the partition
+	ProbeSize int64
+
+	// If non-zero, how much of each input FFmpeg may analyse to find its codec parameters
+	AnalyzeDuration time.Duration
+
+	// If true, the video is decoded and re-encoded (rather than copied) with its wall-clock time drawn onto each frame
+	BurnTimestamp bool
+
+	// If true, video is muxed at its measured average framerate rather than the whole-number rate detected from its
+	// first frames, so the MP4's duration matches the wall-clock duration of the recording
+	UseAverageRate bool
+
+	// The -loglevel to run FFmpeg with (defaults to "warning" if empty)
+	LogLevel string
+
+	// If non-zero, the number of threads FFmpeg may use to decode and encode video. Only used when BurnTimestamp is set,
+	// since a plain remux copies the bitstream without decoding it
+	Threads int
+
+	// If non-empty, the FFmpeg input format to read the raw video bitstream as (one of VIDEO_CODECS), rather than
+	// letting FFmpeg probe it
+	VideoCodec string
+
+	// If non-empty, the FFmpeg input format to read the raw audio bitstream as (one of AUDIO_CODECS), rather than
+	// letting FFmpeg probe it
+	AudioCodec string
+
+	// Added to the offset applied to the audio when muxing it with video, for recordings whose audio consistently
+	// leads (use a positive delay) or lags (negative) the video
+	AudioDelay time.Duration
+}
+
+// The raw video bitstream formats that may be forced with MuxOptions.VideoCodec
+var VIDEO_CODECS = []string{"h264", "hevc"}
+
+// The raw audio bitstream formats that may be forced with MuxOptions.AudioCodec
+var AUDIO_CODECS = []string{"aac", "alaw"}
+
+// Codecs that can't be forced, and why. The demuxer can only produce raw bitstreams FFmpeg knows how to read: video as
+// Annex B NAL units, audio as packets written back to back
+var UNSUPPORTED_VIDEO_CODECS = map[string]string{
+	"av1": "AV1 is made up of OBUs rather than NAL units, so can't be demuxed to a raw bitstream",
+}
+var UNSUPPORTED_AUDIO_CODECS = map[string]string{
+	"opus": "Opus packets carry no framing of their own, so FFmpeg can't read them back as a raw bitstream",
+}
+
+// The framerate video will be muxed at
+func (opts MuxOptions) videoRate(videoTrack *ubv.UbvTrack) float64 {
+	if opts.UseAverageRate {
+		if average := videoTrack.AverageRate(); average > 0 {
+			return average
+		}
+	}
+
+	return float64(videoTrack.Rate)
+}
+
+// The framerate video will be muxed at, formatted for an FFmpeg -r argument
+func (opts MuxOptions) videoRateArg(videoTrack *ubv.UbvTrack) string {
+	return strconv.FormatFloat(opts.videoRate(videoTrack), 'f', -1, 64)
+}
+
+// Options for the raw video input. When burning in timestamps the input rate must be set so decoded frames get the
+// right timestamps (otherwise FFmpeg assumes 25fps for a raw H.264 bitstream)
+func (opts MuxOptions) videoInputArgs(videoTrack *ubv.UbvTrack) []string {
+	var args []string
+
+	if len(opts.VideoCodec) > 0 {
+		args = append(args, "-f", opts.VideoCodec)
+	}
+
+	if opts.BurnTimestamp {
+		args = append(args, "-r", opts.videoRateArg(videoTrack))
+		args = append(args, opts.threadArgs()...)
+	}
+
+	return args
+}
+
+// Video codec options, overriding a preceding "-c copy". When burning in timestamps this re-encodes to H.264 with the
+// frame's UTC wall-clock (its pts offset by the track's start timecode) drawn in the top-left corner. A copied HEVC
+// stream is tagged hvc1 (FFmpeg defaults to hev1), which QuickTime and Apple devices require to play it
+func (opts MuxOptions) videoCodecArgs(videoTrack *ubv.UbvTrack) []string {
+	if !opts.BurnTimestamp && opts.VideoCodec == "hevc" {
+		return []string{"-tag:v", "hvc1"}
+	} else if !opts.BurnTimestamp {
+		return nil
+	}
+
+	startSeconds := float64(videoTrack.StartTimecode.UnixNano()) / 1000000000.0
+	overlay := "drawtext=text='%{pts\\:gmtime\\:" + strconv.FormatFloat(startSeconds, 'f', 3, 64) + "}':x=8:y=8:fontcolor=white:box=1:boxcolor=black@0.5"
+
+	return append([]string{"-vf", overlay, "-c:v", "libx264"}, opts.threadArgs()...)
+}
+
+// Options for the raw audio input. Raw A-law has no header, so FFmpeg must be told its sample rate (the track's
+// timebase) and channel count (cameras record mono)
+func (opts MuxOptions) audioInputArgs(audioTrack *ubv.UbvTrack) []string {
+	if len(opts.AudioCodec) == 0 {
+		return nil
+	}
+
+	args := []string{"-f", opts.AudioCodec}
+	if opts.AudioCodec == "alaw" {
+		args = append(args, "-ar", strconv.Itoa(audioTrack.Rate), "-ac", "1")
+	}
+
+	return args
+}
+
+// Audio codec options, overriding a preceding "-c copy". MP4 can't hold A-law, so it's encoded to AAC
+func (opts MuxOptions) audioCodecArgs() []string {
+	if opts.AudioCodec == "alaw" {
+		return []string{"-c:a", "aac"}
+	}
+
+	return nil
+}
+
+// Thread count for FFmpeg's decoder (when given before an input) or encoder (when given after)
+func (opts MuxOptions) threadArgs() []string {
+	if opts.Threads <= 0 {
+		return nil
+	}
+
+	return []string{"-threads", strconv.Itoa(opts.Threads)}
+}
+
+// Input options controlling how much of a raw bitstream FFmpeg reads to work out its codec parameters; high bitrate
+// streams can otherwise fail with "Could not find codec parameters"
+func (opts MuxOptions) probeArgs(partition *ubv.UbvPartition) []string {
+	var args []string
+
+	if opts.ProbeSize > 0 {
+		args = append(args, "-probesize", strconv.FormatInt(opts.ProbeSize, 10))
+	} else if probeSize := guessProbeSize(partition); probeSize > FFMPEG_DEFAULT_PROBESIZE {
+		args = append(args, "-probesize", strconv.FormatInt(probeSize, 10))
+	}
+
+	if opts.AnalyzeDuration > 0 {
+		args = append(args, "-analyzeduration", strconv.FormatInt(opts.AnalyzeDuration.Microseconds(), 10))
+	}
+
+	return args
+}
+
+// Returns the number of bytes up to and including the first PROBE_FRAMES video frames from the first keyframe of a
+// partition. Frames before the first keyframe carry no SPS/PPS, so the probe must reach past them to find parameters
+func guessProbeSize(partition *ubv.UbvPartition) int64 {
+	var size int64
+	leadingFrames := 0
+	frames := 0
+
+	for _, frame := range partition.Frames {
+		if frame.TrackNumber == 7 {
+			size += int64(frame.Size)
+
+			if frames == 0 && !frame.IsKeyframe {
+				leadingFrames++
+				continue
+			}
+
+			frames++
+
+			if frames >= ubv.PROBE_FRAMES {
+				break
+			}
+		}
+	}
+
+	if leadingFrames > 0 {
+		log.Printf("Partition %s has %d video frame(s) before its first keyframe, probing %d bytes", partition.Name(), leadingFrames, size)
+	}
+
+	return size
+}
+
+func MuxVideoOnly(partition *ubv.UbvPartition, h264File string, mp4File string, opts MuxOptions) error {
 	videoTrack := partition.Tracks[7]
 
 	if videoTrack.FrameCount <= 0 {
 		log.Println("Video stream contained zero frames! Skipping this output file: ", mp4File)
-		return
+		return nil
 	}
 
 	if videoTrack.Rate <= 0 {
@@ -21,24 +211,37 @@ func MuxVideoOnly(partition *ubv.UbvPartition, h264File string, mp4File string)
 		videoTrack.Rate = 1
 	}
 
-	cmd := exec.Command(getFfmpegCommand(), "-i", h264File, "-c", "copy", "-r", strconv.Itoa(videoTrack.Rate), "-y", "-loglevel", "warning", mp4File)
+	args := opts.probeArgs(partition)
+	args = append(args, opts.videoInputArgs(videoTrack)...)
+	args = append(args, "-i", h264File, "-c", "copy")
+	args = append(args, opts.videoCodecArgs(videoTrack)...)
+	args = append(args, "-r", opts.videoRateArg(videoTrack))
+	args = append(args, metadataArgs(partition)...)
 
-	runFFmpeg(cmd)
+	return runFFmpeg(args, mp4File, opts)
 }
 
-func MuxAudioOnly(partition *ubv.UbvPartition, aacFile string, mp4File string) {
-	cmd := exec.Command(getFfmpegCommand(), "-i", aacFile, "-c", "copy", "-y", "-loglevel", "warning", mp4File)
+func MuxAudioOnly(partition *ubv.UbvPartition, aacFile string, mp4File string, opts MuxOptions) error {
+	if partition.Tracks[1000].FrameCount <= 0 {
+		log.Println("Audio stream contained zero frames! Skipping this output file: ", mp4File)
+		return nil
+	}
 
-	runFFmpeg(cmd)
+	args := opts.probeArgs(partition)
+	args = append(args, opts.audioInputArgs(partition.Tracks[1000])...)
+	args = append(args, "-i", aacFile, "-c", "copy")
+	args = append(args, opts.audioCodecArgs()...)
+	args = append(args, metadataArgs(partition)...)
+
+	return runFFmpeg(args, mp4File, opts)
 }
 
-func MuxAudioAndVideo(partition *ubv.UbvPartition, h264File string, aacFile string, mp4File string) {
+func MuxAudioAndVideo(partition *ubv.UbvPartition, h264File string, aacFile string, mp4File string, opts MuxOptions) error {
 	// If there is no audio file, fall back to the video-only mux operation
 	if len(aacFile) <= 0 {
-		MuxVideoOnly(partition, h264File, mp4File)
-		return
+		return MuxVideoOnly(partition, h264File, mp4File, opts)
 	} else if len(h264File) <= 0 {
-		MuxAudioOnly(partition, aacFile, mp4File)
+		return MuxAudioOnly(partition, aacFile, mp4File, opts)
 	}
 
 	videoTrack := partition.Tracks[7]
@@ -46,32 +249,87 @@ func MuxAudioAndVideo(partition *ubv.UbvPartition, h264File string, aacFile stri
 
 	if videoTrack.FrameCount <= 0 || audioTrack.FrameCount <= 0 {
 		log.Println("Audio/Video stream contained zero frames! Skipping this output file: ", mp4File)
-		return
+		return nil
 	}
 
-	audioDelaySec := float64(videoTrack.StartTimecode.UnixNano()-audioTrack.StartTimecode.UnixNano()) / 1000000000.0
+	audioDelaySec := audioDelay(videoTrack, audioTrack) + opts.AudioDelay.Seconds()
 
 	if videoTrack.Rate <= 0 {
 		log.Println("Invalid guessed Video framerate of ", videoTrack.Rate, " for ", mp4File, ". Setting to 1")
 		videoTrack.Rate = 1
 	}
 
-	cmd := exec.Command(getFfmpegCommand(), "-i", h264File, "-itsoffset", strconv.FormatFloat(audioDelaySec, 'f', -1, 32), "-i", aacFile, "-map", "0:v", "-map", "1:a", "-c", "copy", "-r", strconv.Itoa(videoTrack.Rate), "-y", "-loglevel", "warning", mp4File)
+	probeArgs := opts.probeArgs(partition)
+
+	args := append([]string{}, probeArgs...)
+	args = append(args, opts.videoInputArgs(videoTrack)...)
+	args = append(args, "-i", h264File, "-itsoffset", strconv.FormatFloat(audioDelaySec, 'f', -1, 32))
+	args = append(args, probeArgs...)
+	args = append(args, opts.audioInputArgs(audioTrack)...)
+	args = append(args, "-i", aacFile, "-map", "0:v", "-map", "1:a", "-c", "copy")
+	args = append(args, opts.videoCodecArgs(videoTrack)...)
+	args = append(args, opts.audioCodecArgs()...)
+	args = append(args, "-r", opts.videoRateArg(videoTrack))
+	args = append(args, metadataArgs(partition)...)
+
+	return runFFmpeg(args, mp4File, opts)
+}
 
-	runFFmpeg(cmd)
+// The -itsoffset (in seconds) applied to the audio input to line it up with the video
+func audioDelay(videoTrack *ubv.UbvTrack, audioTrack *ubv.UbvTrack) float64 {
+	return float64(videoTrack.StartTimecode.UnixNano()-audioTrack.StartTimecode.UnixNano()) / 1000000000.0
 }
 
-func runFFmpeg(cmd *exec.Cmd) {
+// Output options tagging the MP4 with the wall-clock time the partition starts at, so players and media libraries
+// can sort it by capture time without relying on the filename
+func metadataArgs(partition *ubv.UbvPartition) []string {
+	if partition.StartTimecode.IsZero() {
+		return nil
+	}
+
+	return []string{"-metadata", "creation_time=" + partition.StartTimecode.UTC().Format(time.RFC3339Nano)}
+}
+
+// Runs FFmpeg with the given input/codec args, writing to a temporary ".partial" file which is only renamed to mp4File
+// once FFmpeg succeeds. This means an interrupted run never leaves a truncated MP4 under the final name
+func runFFmpeg(args []string, mp4File string, opts MuxOptions) error {
+	partialFile := mp4File + PARTIAL_SUFFIX
+
+	logLevel := opts.LogLevel
+	if len(logLevel) == 0 {
+		logLevel = "warning"
+	}
+
+	// N.B. the format must be given explicitly, FFmpeg can't infer it from the .partial extension
+	args = append(args, "-f", "mp4", "-y", "-loglevel", logLevel, partialFile)
+
+	ffmpeg, err := getFfmpegCommand()
+	if err != nil {
+		return err
+	}
+
+	cmd := exec.Command(ffmpeg, args...)
+
 	log.Println("Running: ", cmd.Args)
 
-	// Pass through stdout and stderr
-	cmd.Stdout = os.Stdout
-	cmd.Stderr = os.Stderr
+	// Pass through stdout and stderr to wherever our own log output is going (which may include a -log-file)
+	cmd.Stdout = log.Writer()
+	cmd.Stderr = log.Writer()
 
-	err := cmd.Run()
+	err = cmd.Run()
 	if err != nil {
-		log.Fatal("FFmpeg command failed! Error: ", err)
+		if err := os.Remove(partialFile); err != nil && !os.IsNotExist(err) {
+			log.Println("Warning: could not delete ", partialFile+": ", err)
+		}
+
+		return fmt.Errorf("FFmpeg command failed: %v", err)
+	}
+
+	if err := os.Rename(partialFile, mp4File); err != nil {
+		return fmt.Errorf("could not rename %s to %s: %v", partialFile, mp4File, err)
 	}
+
+	return nil
 }
 
 const (
@@ -80,18 +338,15 @@ const (
 	FFMPEG_LOC_3 = "/root/ffmpeg-4.3.1-arm64-static/ffmpeg"
 )
 
-// Looks for ubnt_ubvinfo on the path and in the default Protect install location
-func getFfmpegCommand() string {
+// Looks for FFmpeg on the path and in the default locations
+func getFfmpegCommand() (string, error) {
 	paths := [...]string{FFMPEG_LOC_1, FFMPEG_LOC_2, FFMPEG_LOC_3}
 
 	for _, path := range paths {
 		if _, err := exec.LookPath(path); err == nil {
-			return path
+			return path, nil
 		}
 	}
 
-	log.Fatal("FFmpeg not on PATH, nor in any default search locations!")
-
-	// Keep compiler happy (log.Fatal above exits)
-	return paths[0]
+	return "", fmt.Errorf("FFmpeg not on PATH, nor in any default search locations")
 }
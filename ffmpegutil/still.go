@@ -0,0 +1,76 @@
+package ffmpegutil
+
+import (
+	"fmt"
+	"os/exec"
+	"strconv"
+)
+
+// Options for extracting still images
+type StillOptions struct {
+	// If non-zero, stills wider or taller than these are scaled down to fit (preserving aspect ratio)
+	MaxWidth  int
+	MaxHeight int
+
+	// The image format to write (one of STILL_FORMATS), which is also the file extension. Defaults to "jpg" if empty
+	Format string
+
+	// If non-empty, the FFmpeg input format to read the raw video bitstream as (see MuxOptions.VideoCodec)
+	VideoCodec string
+}
+
+// The image formats stills may be written in
+var STILL_FORMATS = []string{"jpg", "png"}
+
+// The image format (and file extension) stills will be written in
+func (opts StillOptions) FormatOrDefault() string {
+	if len(opts.Format) == 0 {
+		return "jpg"
+	}
+
+	return opts.Format
+}
+
+// Decodes a raw video bitstream (which must start with a keyframe) and writes the frame at frameIndex to an image
+func ExtractStill(videoFile string, frameIndex int, imageFile string, opts StillOptions) error {
+	filter := "select=eq(n\\," + strconv.Itoa(frameIndex) + ")"
+
+	// Fit within the bounding box without upscaling, keeping dimensions even (as some encoders require)
+	if opts.MaxWidth > 0 || opts.MaxHeight > 0 {
+		filter += ",scale=w=" + maxDimension(opts.MaxWidth, "iw") + ":h=" + maxDimension(opts.MaxHeight, "ih") + ":force_original_aspect_ratio=decrease:force_divisible_by=2"
+	}
+
+	var args []string
+	if len(opts.VideoCodec) > 0 {
+		args = append(args, "-f", opts.VideoCodec)
+	}
+
+	args = append(args, "-i", videoFile, "-vf", filter, "-frames:v", "1")
+
+	// FFmpeg picks the encoder from the extension, but PNG needs an RGB pixel format (the video is YUV)
+	if opts.FormatOrDefault() == "png" {
+		args = append(args, "-pix_fmt", "rgb24")
+	}
+
+	ffmpeg, err := getFfmpegCommand()
+	if err != nil {
+		return err
+	}
+
+	cmd := exec.Command(ffmpeg, append(args, "-y", "-loglevel", "warning", imageFile)...)
+
+	if out, err := cmd.CombinedOutput(); err != nil {
+		return fmt.Errorf("FFmpeg failed: %v: %s", err, out)
+	}
+
+	return nil
+}
+
+// A scale filter dimension limited to limit (unless limit is 0), given the input dimension
+func maxDimension(limit int, input string) string {
+	if limit <= 0 {
+		return input
+	}
+
+	return "min(" + strconv.Itoa(limit) + "\\," + input + ")"
+}
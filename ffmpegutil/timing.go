@@ -0,0 +1,90 @@
+package ffmpegutil
+
+import (
+	"encoding/csv"
+	"io"
+	"strconv"
+	"ubvremux/ubv"
+)
+
+// Number of samples in each AAC packet
+const AAC_SAMPLES_PER_PACKET = 1024
+
+// Writes a CSV of the timestamps each frame is expected to have in the MP4s FFmpeg writes, alongside the DTS from the
+// .ubv. N.B. the expected timestamps are modelled on the rate and offsets FFmpeg is invoked with, not read back from the
+// MP4, so they show what the remux intends rather than confirming what FFmpeg did
+type TimingWriter struct {
+	csv *csv.Writer
+}
+
+// Creates a TimingWriter, writing the CSV header
+func NewTimingWriter(w io.Writer) (*TimingWriter, error) {
+	timing := &TimingWriter{csv: csv.NewWriter(w)}
+
+	if err := timing.csv.Write([]string{"output", "partition", "stream", "frame_index", "input_dts", "expected_pts", "expected_duration", "keyframe"}); err != nil {
+		return nil, err
+	}
+
+	return timing, nil
+}
+
+// Writes a row for each frame of a partition muxed into mp4File. Expected timestamps and durations are in seconds: video
+// is muxed at a fixed rate from 0, audio packets follow each other from the audio's -itsoffset
+func (t *TimingWriter) WritePartition(partition *ubv.UbvPartition, hasVideo bool, hasAudio bool, mp4File string, opts MuxOptions) error {
+	var frameDuration float64
+	if hasVideo {
+		frameDuration = 1 / opts.videoRate(partition.Tracks[7])
+	}
+
+	var packetDuration float64
+	var audioStart float64
+	if hasAudio {
+		audioTrack := partition.Tracks[1000]
+
+		if audioTrack.Rate > 0 {
+			packetDuration = float64(AAC_SAMPLES_PER_PACKET) / float64(audioTrack.Rate)
+		}
+
+		if hasVideo {
+			audioStart = audioDelay(partition.Tracks[7], audioTrack) + opts.AudioDelay.Seconds()
+		}
+	}
+
+	videoFrames := 0
+	audioPackets := 0
+
+	for _, frame := range partition.Frames {
+		var row []string
+
+		if frame.TrackNumber == 7 && hasVideo {
+			row = timingRow(mp4File, partition, "video", videoFrames, frame, float64(videoFrames)*frameDuration, frameDuration)
+			videoFrames++
+		} else if frame.TrackNumber == 1000 && hasAudio {
+			row = timingRow(mp4File, partition, "audio", audioPackets, frame, audioStart+float64(audioPackets)*packetDuration, packetDuration)
+			audioPackets++
+		} else {
+			continue
+		}
+
+		if err := t.csv.Write(row); err != nil {
+			return err
+		}
+	}
+
+	t.csv.Flush()
+
+	return t.csv.Error()
+}
+
+func timingRow(mp4File string, partition *ubv.UbvPartition, stream string, index int, frame ubv.UbvFrame, expectedPts float64, duration float64) []string {
+	return []string{
+		mp4File,
+		partition.Name(),
+		stream,
+		strconv.Itoa(index),
+		strconv.FormatInt(frame.Dts, 10),
+		strconv.FormatFloat(expectedPts, 'f', 6, 64),
+		strconv.FormatFloat(duration, 'f', 6, 64),
+		strconv.FormatBool(frame.IsKeyframe),
+	}
+}
@@ -0,0 +1,118 @@
+package ffmpegutil
+
+import (
+	"encoding/json"
+	"fmt"
+	"log"
+	"math"
+	"os/exec"
+	"strconv"
+	"ubvremux/ubv"
+)
+
+const (
+	FFPROBE_LOC_1 = "ffprobe"
+	FFPROBE_LOC_2 = "/root/ffprobe"
+	FFPROBE_LOC_3 = "/root/ffmpeg-4.3.1-arm64-static/ffprobe"
+
+	// The smallest allowed difference (in seconds) between the MP4 duration and the analysed duration
+	VERIFY_MIN_DURATION_TOLERANCE = 1.0
+
+	// The allowed difference between the MP4 duration and the analysed duration, as a fraction of the analysed duration
+	VERIFY_DURATION_TOLERANCE = 0.05
+)
+
+// The subset of "ffprobe -of json" output needed to verify an MP4
+type probeResult struct {
+	Streams []struct {
+		CodecType string `json:"codec_type"`
+		Width     int    `json:"width"`
+		Height    int    `json:"height"`
+	} `json:"streams"`
+	Format struct {
+		Duration string `json:"duration"`
+	} `json:"format"`
+}
+
+// Re-opens a freshly written MP4 with FFprobe and checks it has the expected streams, non-zero video dimensions and
+// a duration close to the one implied by the partition analysis
+func VerifyMP4(partition *ubv.UbvPartition, hasVideo bool, hasAudio bool, mp4File string, opts MuxOptions) error {
+	ffprobe, err := getFfprobeCommand()
+	if err != nil {
+		return err
+	}
+
+	cmd := exec.Command(ffprobe, "-v", "error", "-show_entries", "stream=codec_type,width,height:format=duration", "-of", "json", mp4File)
+
+	out, err := cmd.Output()
+	if err != nil {
+		return fmt.Errorf("ffprobe failed on %s: %v", mp4File, err)
+	}
+
+	var result probeResult
+	if err := json.Unmarshal(out, &result); err != nil {
+		return fmt.Errorf("could not parse ffprobe output for %s: %v", mp4File, err)
+	}
+
+	videoStreams := 0
+	audioStreams := 0
+	for _, stream := range result.Streams {
+		switch stream.CodecType {
+		case "video":
+			videoStreams++
+
+			if stream.Width <= 0 || stream.Height <= 0 {
+				return fmt.Errorf("video stream has invalid dimensions %dx%d", stream.Width, stream.Height)
+			}
+		case "audio":
+			audioStreams++
+		}
+	}
+
+	if (videoStreams > 0) != hasVideo || (audioStreams > 0) != hasAudio {
+		return fmt.Errorf("found %d video and %d audio streams, expected video=%t audio=%t", videoStreams, audioStreams, hasVideo, hasAudio)
+	}
+
+	duration, err := strconv.ParseFloat(result.Format.Duration, 64)
+	if err != nil {
+		return fmt.Errorf("could not parse duration %q: %v", result.Format.Duration, err)
+	}
+
+	expected := expectedDuration(partition, hasVideo, opts)
+	tolerance := math.Max(VERIFY_MIN_DURATION_TOLERANCE, expected*VERIFY_DURATION_TOLERANCE)
+
+	if math.Abs(duration-expected) > tolerance {
+		return fmt.Errorf("duration %.3fs differs from expected %.3fs by more than %.3fs", duration, expected, tolerance)
+	}
+
+	log.Printf("MP4 has %d video and %d audio streams, duration %.3fs (expected %.3fs)", videoStreams, audioStreams, duration, expected)
+
+	return nil
+}
+
+// The duration (in seconds) FFmpeg should produce for a partition. Video is muxed at a fixed rate, so this is based on
+// the frame count rather than the wall-clock (which may disagree if the rate was forced)
+func expectedDuration(partition *ubv.UbvPartition, hasVideo bool, opts MuxOptions) float64 {
+	if hasVideo {
+		videoTrack := partition.Tracks[7]
+
+		return float64(videoTrack.FrameCount) / opts.videoRate(videoTrack)
+	} else {
+		audioTrack := partition.Tracks[1000]
+
+		return audioTrack.LastTimecode.Sub(audioTrack.StartTimecode).Seconds()
+	}
+}
+
+// Looks for ffprobe on the path and alongside the default FFmpeg locations
+func getFfprobeCommand() (string, error) {
+	paths := [...]string{FFPROBE_LOC_1, FFPROBE_LOC_2, FFPROBE_LOC_3}
+
+	for _, path := range paths {
+		if _, err := exec.LookPath(path); err == nil {
+			return path, nil
+		}
+	}
+
+	return "", fmt.Errorf("FFprobe not on PATH, nor in any default search locations")
+}
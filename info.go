@@ -0,0 +1,156 @@
+package main
+
+import (
+	"fmt"
+	"os"
+	"sort"
+	"time"
+	"ubvremux/ubv"
+)
+
+// Prints the tracks found in each partition of each file, without extracting anything. opts should match the remux
+// (other than always including audio) so the frames listed are those that would be extracted
+func ListTracksCLI(files []string, opts ubv.ParseOptions) {
+	for _, ubvFile := range files {
+		info, err := ubv.Analyse(ubvFile, opts)
+		if err != nil {
+			fmt.Fprintf(os.Stderr, "%s: analysis failed: %v\n", ubvFile, err)
+			continue
+		}
+
+		fmt.Printf("%s: %d partition(s)\n", ubvFile, len(info.Partitions))
+
+		// N.B. the .ubv itself may be absent if analysing a cached .ubv.txt, in which case audio parameters are omitted
+		ubvReader, err := os.Open(ubvFile)
+		if err != nil {
+			ubvReader = nil
+		}
+
+		for _, partition := range info.Partitions {
+			fmt.Printf("\tPartition %d: %s from %s to %s\n",
+				partition.Index,
+				formatDuration(partition.Duration()),
+				partition.StartTimecode.Format(time.RFC3339Nano),
+				partition.EndTimecode.Format(time.RFC3339Nano))
+
+			trackBytes := make(map[int]int64)
+			for _, frame := range partition.Frames {
+				trackBytes[frame.TrackNumber] += int64(frame.Size)
+			}
+
+			for _, track := range sortedTracks(partition) {
+				kind := "audio"
+				if track.IsVideo {
+					kind = "video"
+				}
+
+				fmt.Printf("\t\tTrack %d: %s (%s), %d frames, %s, %s from %s to %s\n",
+					track.TrackNumber,
+					kind,
+					ubv.FileExtensionForTrack(track.TrackNumber),
+					track.FrameCount,
+					formatBytes(trackBytes[track.TrackNumber]),
+					formatDuration(track.LastTimecode.Sub(track.StartTimecode)),
+					track.StartTimecode.Format(time.RFC3339Nano),
+					track.LastTimecode.Format(time.RFC3339Nano))
+
+				if track.ClockJumps > 0 {
+					fmt.Printf("\t\t\t%d wall-clock jump(s), timecodes may be unreliable\n", track.ClockJumps)
+				}
+
+				if track.IsVideo {
+					fmt.Printf("\t\t\t%d keyframes, GOP length average %.1f, max %d frames\n", track.KeyframeCount, track.AverageGopLength(), track.MaxGopLength)
+				}
+
+				if !track.IsVideo && ubvReader != nil {
+					if params, err := partition.AudioParams(ubvReader); err != nil {
+						fmt.Printf("\t\t\tAudio parameters unavailable: %v\n", err)
+					} else {
+						fmt.Printf("\t\t\t%s\n", params)
+					}
+				}
+			}
+		}
+
+		if ubvReader != nil {
+			ubvReader.Close()
+		}
+	}
+}
+
+// Prints the number of partitions and video/audio frames in each file, without extracting anything. As with
+// ListTracksCLI, opts should match the remux so the counts are of the frames that would be extracted
+func CountFramesCLI(files []string, opts ubv.ParseOptions) {
+	for _, ubvFile := range files {
+		info, err := ubv.Analyse(ubvFile, opts)
+		if err != nil {
+			fmt.Fprintf(os.Stderr, "%s: analysis failed: %v\n", ubvFile, err)
+			continue
+		}
+
+		var lines []string
+		var videoFrames int
+		var audioFrames int
+
+		for _, partition := range info.Partitions {
+			var partitionVideo int
+			var partitionAudio int
+
+			for _, track := range partition.Tracks {
+				if track.IsVideo {
+					partitionVideo += track.FrameCount
+				} else {
+					partitionAudio += track.FrameCount
+				}
+			}
+
+			lines = append(lines, fmt.Sprintf("\tPartition %d: %d video, %d audio\n", partition.Index, partitionVideo, partitionAudio))
+			videoFrames += partitionVideo
+			audioFrames += partitionAudio
+		}
+
+		fmt.Printf("%s: %d partition(s), %d video, %d audio\n", ubvFile, len(info.Partitions), videoFrames, audioFrames)
+
+		for _, line := range lines {
+			fmt.Print(line)
+		}
+	}
+}
+
+// Returns the tracks of a partition in track number order
+func sortedTracks(partition *ubv.UbvPartition) []*ubv.UbvTrack {
+	var trackNumbers []int
+	for trackNumber := range partition.Tracks {
+		trackNumbers = append(trackNumbers, trackNumber)
+	}
+
+	sort.Ints(trackNumbers)
+
+	tracks := make([]*ubv.UbvTrack, 0, len(trackNumbers))
+	for _, trackNumber := range trackNumbers {
+		tracks = append(tracks, partition.Tracks[trackNumber])
+	}
+
+	return tracks
+}
+
+// Formats a duration as HH:MM:SS.mmm
+func formatDuration(d time.Duration) string {
+	millis := d.Milliseconds()
+
+	return fmt.Sprintf("%02d:%02d:%02d.%03d", millis/3600000, (millis/60000)%60, (millis/1000)%60, millis%1000)
+}
+
+// Formats a byte count using binary (KiB/MiB/GiB) units
+func formatBytes(bytes int64) string {
+	switch {
+	case bytes >= 1024*1024*1024:
+		return fmt.Sprintf("%.1f GiB", float64(bytes)/(1024*1024*1024))
+	case bytes >= 1024*1024:
+		return fmt.Sprintf("%.1f MiB", float64(bytes)/(1024*1024))
+	case bytes >= 1024:
+		return fmt.Sprintf("%.1f KiB", float64(bytes)/1024)
+	default:
+		return fmt.Sprintf("%d B", bytes)
+	}
+}
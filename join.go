@@ -0,0 +1,230 @@
+package main
+
+import (
+	"bytes"
+	"fmt"
+	"log"
+	"os"
+	"runtime/debug"
+	"sort"
+	"ubvremux/demux"
+	"ubvremux/ffmpegutil"
+	"ubvremux/ubv"
+)
+
+// A partition to be joined, and the .ubv it was read from
+type joinPart struct {
+	ubvFile   string
+	ubvReader *os.File
+	partition *ubv.UbvPartition
+}
+
+// Remuxes .ubv files that are parts of one recording (e.g. a long recording split across several files) as one
+// continuous timeline. The files are ordered by the timestamp in their filenames, and consecutive partitions are
+// demuxed into the same raw bitstreams and muxed once, for as long as their video and audio formats match. Where the
+// format changes the mismatch is reported as an error and a new output started. Returns the (possibly grown) frame
+// buffer
+func remuxJoined(files []string, config RemuxConfig, report *FileReport, timing *ffmpegutil.TimingWriter, claimed map[string]string, frameBuffer []byte) (buffer []byte) {
+	buffer = frameBuffer
+
+	// The outputs of the joined partitions being processed, which are incomplete (so deleted on failure) until finished
+	var partialOutputs []string
+
+	defer func() {
+		if r := recover(); r != nil {
+			log.Printf("Error: unexpected failure joining %v: %v\n%s", files, r, debug.Stack())
+			report.Errors = append(report.Errors, fmt.Sprint("unexpected failure: ", r))
+
+			removeFiles(partialOutputs...)
+		}
+	}()
+
+	files, err := sortByFilenameTimecode(files)
+	if err != nil {
+		log.Println("Error: ", err)
+		report.Errors = append(report.Errors, err.Error())
+
+		return buffer
+	}
+
+	var parts []joinPart
+
+	for _, ubvFile := range files {
+		log.Println("Analysing ", ubvFile)
+		info, err := ubv.Analyse(ubvFile, ubv.ParseOptions{
+			IncludeAudio:    config.ExtractAudio,
+			StartAtKeyframe: config.StartAtKeyframe,
+			VideoClockRate:  config.VideoClockRate,
+		})
+		if err != nil {
+			log.Println("Error: could not analyse ", ubvFile, ": ", err)
+			report.Errors = append(report.Errors, fmt.Sprintf("analysis of %s failed: %v", ubvFile, err))
+
+			return buffer
+		}
+
+		if config.Checksums {
+			if digest, err := digestFile(ubvFile, info, config); err != nil {
+				log.Println("Error: failed to compute checksum of ", ubvFile, ": ", err)
+				report.Errors = append(report.Errors, fmt.Sprintf("failed to compute checksum of %s: %v", ubvFile, err))
+			} else {
+				log.Printf("%s essence SHA-256: %x", ubvFile, digest)
+			}
+		}
+
+		adjustPartitions(info.Partitions, &config)
+
+		ubvReader, err := demux.OpenUbv(ubvFile)
+		if err != nil {
+			log.Println("Error: could not open ", ubvFile, ": ", err)
+			report.Errors = append(report.Errors, fmt.Sprintf("could not open %s: %v", ubvFile, err))
+
+			return buffer
+		}
+
+		// N.B. every file is kept open until the joined outputs have been written
+		defer ubvReader.Close()
+
+		for _, partition := range info.Partitions {
+			parts = append(parts, joinPart{ubvFile, ubvReader, partition})
+		}
+	}
+
+	// Group consecutive partitions whose formats match
+	var groups [][]joinPart
+
+	for i, part := range parts {
+		if i > 0 {
+			group := groups[len(groups)-1]
+
+			if err := checkJoinable(group[0], part, videoExtension(config), audioExtension(config)); err != nil {
+				message := fmt.Sprintf("cannot join partition %s of %s onto partition %s of %s, starting a new output: %v", part.partition.Name(), part.ubvFile, group[0].partition.Name(), group[0].ubvFile, err)
+				log.Println("Error: ", message)
+				report.Errors = append(report.Errors, message)
+			} else {
+				groups[len(groups)-1] = append(group, part)
+				continue
+			}
+		}
+
+		groups = append(groups, []joinPart{part})
+	}
+
+	for _, group := range groups {
+		var ubvReaders []*os.File
+		var partitions []*ubv.UbvPartition
+		for _, part := range group {
+			ubvReaders = append(ubvReaders, part.ubvReader)
+			partitions = append(partitions, part.partition)
+		}
+
+		joined := ubv.JoinPartitions(partitions)
+
+		videoFile, audioFile, mp4, err := partitionOutputs(group[0].ubvFile, joined, config, claimed)
+		if err != nil {
+			log.Println("Error: ", err)
+			report.Errors = append(report.Errors, err.Error())
+			continue
+		} else if existing := existingOutput(videoFile, audioFile, mp4, config); len(existing) > 0 {
+			log.Println("Skipping joined output, it already exists: ", existing)
+			continue
+		}
+
+		log.Printf("\n\nJoining %d partition(s) from partition %s of %s to partition %s of %s", len(group), group[0].partition.Name(), group[0].ubvFile, group[len(group)-1].partition.Name(), group[len(group)-1].ubvFile)
+
+		partialOutputs = partialFiles(videoFile, audioFile, mp4, config)
+
+		buffer, err = demux.DemuxPartitionsToNewFiles(ubvReaders, partitions, videoFile, audioFile, videoExtension(config), buffer)
+		if err == nil {
+			err = muxPartition(joined, videoFile, audioFile, mp4, config, report, timing)
+		}
+
+		if err != nil {
+			log.Println("Error: failed to remux joined partitions starting at partition ", joined.Name(), " of ", group[0].ubvFile, ": ", err)
+			report.Errors = append(report.Errors, fmt.Sprintf("joined partitions starting at partition %s of %s: %v", joined.Name(), group[0].ubvFile, err))
+
+			removeFiles(partialOutputs...)
+		}
+
+		partialOutputs = nil
+
+		if config.FailFast && len(report.Errors) > 0 {
+			log.Println("Stopping at first failure (-fail-fast)")
+			break
+		}
+	}
+
+	return buffer
+}
+
+// Returns an error if part can't be appended to an output that began with first, because its video or audio format
+// differs (FFmpeg takes the codec parameters for the whole MP4 from the start of each stream). The video and audio of
+// both are read as videoCodec ("h264" or "hevc") and audioCodec ("aac" or "alaw")
+func checkJoinable(first joinPart, part joinPart, videoCodec string, audioCodec string) error {
+	if (first.partition.VideoTrackCount > 0) != (part.partition.VideoTrackCount > 0) {
+		return fmt.Errorf("one has video and the other doesn't")
+	} else if (first.partition.AudioTrackCount > 0) != (part.partition.AudioTrackCount > 0) {
+		return fmt.Errorf("one has audio and the other doesn't")
+	}
+
+	if first.partition.VideoTrackCount > 0 {
+		// N.B. an SPS is only found if the video is of the expected codec, so a file of another codec fails here
+		firstSPS, err := first.partition.FirstSPS(first.ubvReader, videoCodec)
+		if err != nil {
+			return fmt.Errorf("could not read the video codec parameters of %s (is its video another codec?): %v", first.ubvFile, err)
+		}
+
+		sps, err := part.partition.FirstSPS(part.ubvReader, videoCodec)
+		if err != nil {
+			return fmt.Errorf("could not read the video codec parameters of %s (is its video another codec?): %v", part.ubvFile, err)
+		}
+
+		// The SPS holds the codec profile and resolution
+		if !bytes.Equal(firstSPS, sps) {
+			return fmt.Errorf("video codec parameters (SPS) differ, was the resolution or encoder changed?")
+		}
+	}
+
+	// N.B. only AAC has a header to read the parameters from, otherwise just the sample rates can be compared
+	if first.partition.AudioTrackCount > 0 && audioCodec != "aac" {
+		if firstRate, rate := first.partition.Tracks[1000].Rate, part.partition.Tracks[1000].Rate; firstRate != rate {
+			return fmt.Errorf("audio sample rates differ (%d and %d Hz)", firstRate, rate)
+		}
+	} else if first.partition.AudioTrackCount > 0 {
+		firstParams, err := first.partition.AudioParams(first.ubvReader)
+		if err != nil {
+			return fmt.Errorf("could not read audio parameters: %v", err)
+		}
+
+		params, err := part.partition.AudioParams(part.ubvReader)
+		if err != nil {
+			return fmt.Errorf("could not read audio parameters: %v", err)
+		}
+
+		if firstParams != params {
+			return fmt.Errorf("audio formats differ (%s and %s)", firstParams, params)
+		}
+	}
+
+	return nil
+}
+
+// Orders .ubv files by the epoch millis timestamp at the end of their filenames, failing if any doesn't have one
+func sortByFilenameTimecode(files []string) ([]string, error) {
+	sorted := append([]string(nil), files...)
+
+	for _, file := range sorted {
+		if _, ok := ubv.TimecodeFromFilename(file); !ok {
+			return nil, fmt.Errorf("can't order %s for joining: no timestamp at the end of its filename", file)
+		}
+	}
+
+	sort.SliceStable(sorted, func(i, j int) bool {
+		a, _ := ubv.TimecodeFromFilename(sorted[i])
+		b, _ := ubv.TimecodeFromFilename(sorted[j])
+
+		return a.Before(b)
+	})
+
+	return sorted, nil
+}
@@ -2,9 +2,13 @@ package main
 
 import (
 	"flag"
+	"fmt"
+	"io"
 	"log"
+	"math"
 	"os"
 	"path"
+	"runtime/debug"
 	"strings"
 	"time"
 	"ubvremux/demux"
@@ -22,9 +26,43 @@ var GitCommit string
 func main() {
 	includeAudioPtr := flag.Bool("with-audio", false, "If true, extract audio")
 	includeVideoPtr := flag.Bool("with-video", true, "If true, extract video")
+	forceVideoCodecPtr := flag.String("force-video-codec", "", "If non-empty, the codec to read the video as (h264 or hevc) rather than letting FFmpeg detect it. For files whose track doesn't match its codec")
+	forceAudioCodecPtr := flag.String("force-audio-codec", "", "If non-empty, the codec to read the audio as (aac, or alaw which is re-encoded to AAC) rather than letting FFmpeg detect it. For files whose track doesn't match its codec")
 	forceRatePtr := flag.Int("force-rate", 0, "If non-zero, adds a -r argument to FFmpeg invocations")
+	startAtKeyframePtr := flag.Bool("start-at-keyframe", true, "If true, drops any video frames preceding the first keyframe of each partition (these can't be decoded)")
+	keyframesOnlyPtr := flag.Bool("keyframes-only", false, "If true, only keyframes are extracted (spread over the original duration) for a small, quick preview")
+	averageRatePtr := flag.Bool("average-rate", false, "If true, muxes video at its measured average framerate (so MP4 duration matches wall-clock) rather than a whole-number rate. Ignored with -force-rate")
+	joinPtr := flag.Bool("join", false, "If true, joins the input files (parts of one recording, ordered by the timestamp in their filenames) into a single output for as long as their video and audio formats match")
+	partitionPtr := flag.Int("partition", -1, "If non-negative, only extracts the partition with this index (see -list-tracks)")
+	segmentDurationPtr := flag.Duration("segment-duration", 0, "If non-zero, splits each partition into separate outputs of (at least) this duration, each starting on a keyframe (e.g. 10m)")
+	videoClockRatePtr := flag.Int64("assume-video-clock-rate", 0, "If non-zero, the timebase assumed for video frames with an invalid (zero) timebase (by default 90000)")
+	audioDelayPtr := flag.Int("audio-delay-ms", 0, "If non-zero, delays the audio by this many milliseconds relative to the video (negative to advance it), for recordings with a consistent A/V sync error")
+	maxFpsPtr := flag.Int("max-fps", 60, "If non-zero, detected framerates above this are replaced with the track's average framerate (capped at this value)")
 	outputFolder := flag.String("output-folder", "./", "The path to output remuxed files to. \"SRC-FOLDER\" to put alongside .ubv files")
+	mkdirPtr := flag.Bool("mkdir", false, "If true, creates the output folder if it doesn't exist")
+	timezonePtr := flag.String("timezone", "utc", "The timezone for the start timecode in output filenames: utc, local, an offset (e.g. +02:00) or a zone name (e.g. Europe/London)")
+	timeFormatPtr := flag.String("time-format", "", "If non-empty, the Go time layout for the start timecode in output filenames (e.g. 20060102_150405), instead of RFC3339 with : replaced by .")
+	baseNamePtr := flag.String("base-name", "", "If non-empty, used instead of the .ubv filename as the prefix of output files (the partition start timecode is still appended)")
 	remuxPtr := flag.Bool("mp4", true, "If true, will create an MP4 as output")
+	keepTempPtr := flag.Bool("keep-temp", false, "If true, leaves the raw .h264/.aac bitstreams on disk after creating an MP4 (useful when reporting FFmpeg errors)")
+	overwritePtr := flag.Bool("overwrite", false, "If true, replaces existing output files. Otherwise partitions whose output already exists are skipped")
+	probeSizePtr := flag.Int64("probe-size", 0, "If non-zero, the number of bytes FFmpeg may read from each input to find codec parameters (by default sized to cover the first video frames)")
+	analyzeDurationPtr := flag.Duration("analyze-duration", 0, "If non-zero, how much of each input FFmpeg may analyse to find codec parameters (e.g. 10s)")
+	burnTimestampPtr := flag.Bool("burn-timestamp", false, "If true, re-encodes the video with its wall-clock time drawn on each frame (much slower than a remux)")
+	threadsPtr := flag.Int("threads", 0, "If non-zero, the number of threads FFmpeg may use to decode and re-encode video with -burn-timestamp (a plain remux doesn't decode, so isn't affected)")
+	checksumsPtr := flag.Bool("checksums", false, "If true, logs SHA-256 digests of the video and audio essence of each partition extracted, and of every frame of each file (whichever are extracted)")
+	failFastPtr := flag.Bool("fail-fast", false, "If true, stops at the first error (e.g. an FFmpeg failure or an MP4 that fails verification) rather than carrying on with the rest")
+	verifyPtr := flag.Bool("verify", false, "If true, re-opens each MP4 after writing and checks its streams and duration with FFprobe")
+	logFilePtr := flag.String("log-file", "", "If non-empty, a file to append all log output (including FFmpeg's) to, in addition to the console")
+	ffmpegLogLevelPtr := flag.String("ffmpeg-log-level", "warning", "The -loglevel to run FFmpeg with (e.g. info or verbose when diagnosing FFmpeg errors)")
+	dumpTimingPtr := flag.String("dump-timing", "", "If non-empty, a file to write a CSV of the input DTS and expected output timestamp (modelled on how FFmpeg is invoked, not read back from the MP4) of every frame muxed into an MP4 to")
+	reportPtr := flag.String("report", "", "If non-empty, a file to write a JSON report of the inputs processed, their outputs and any errors to")
+	frameAtPtr := flag.String("frame-at", "", "If non-empty, writes an image of the video frame at this time (RFC3339, e.g. 2020-08-14T17:17:48Z) instead of remuxing")
+	stillMaxWidthPtr := flag.Int("still-max-width", 0, "If non-zero, stills from -frame-at wider than this are scaled down")
+	stillMaxHeightPtr := flag.Int("still-max-height", 0, "If non-zero, stills from -frame-at taller than this are scaled down")
+	stillFormatPtr := flag.String("still-format", "jpg", "The image format for stills from -frame-at (jpg or png)")
+	countOnlyPtr := flag.Bool("count-only", false, "Print the number of partitions and video/audio frames in the input files (as extracted, so honouring -start-at-keyframe) and quit")
+	listTracksPtr := flag.Bool("list-tracks", false, "List the tracks in each partition of the input files (as extracted, so honouring -start-at-keyframe) and quit")
 	versionPtr := flag.Bool("version", false, "Display version and quit")
 
 	flag.Parse()
@@ -50,108 +88,888 @@ func main() {
 
 		flag.Usage()
 		os.Exit(1)
+	} else if *listTracksPtr {
+		ListTracksCLI(flag.Args(), ubv.ParseOptions{IncludeAudio: true, StartAtKeyframe: *startAtKeyframePtr, VideoClockRate: *videoClockRatePtr})
+
+		os.Exit(0)
+	} else if *countOnlyPtr {
+		CountFramesCLI(flag.Args(), ubv.ParseOptions{IncludeAudio: true, StartAtKeyframe: *startAtKeyframePtr, VideoClockRate: *videoClockRatePtr})
+
+		os.Exit(0)
 	} else if !*includeAudioPtr && !*includeVideoPtr {
 		// Fail if extracting neither audio nor video
 		println("Must enable extraction of at least one of: audio, video!\n")
 
 		flag.Usage()
 		os.Exit(1)
+	} else if reason, ok := ffmpegutil.UNSUPPORTED_VIDEO_CODECS[*forceVideoCodecPtr]; ok {
+		println("Cannot force video codec ", *forceVideoCodecPtr, ": ", reason, "\n")
+
+		flag.Usage()
+		os.Exit(1)
+	} else if len(*forceVideoCodecPtr) > 0 && !contains(ffmpegutil.VIDEO_CODECS, *forceVideoCodecPtr) {
+		println("Unknown video codec ", *forceVideoCodecPtr, ", expected one of: ", strings.Join(ffmpegutil.VIDEO_CODECS, ", "), "\n")
+
+		flag.Usage()
+		os.Exit(1)
+	} else if reason, ok := ffmpegutil.UNSUPPORTED_AUDIO_CODECS[*forceAudioCodecPtr]; ok {
+		println("Cannot force audio codec ", *forceAudioCodecPtr, ": ", reason, "\n")
+
+		flag.Usage()
+		os.Exit(1)
+	} else if len(*forceAudioCodecPtr) > 0 && !contains(ffmpegutil.AUDIO_CODECS, *forceAudioCodecPtr) {
+		println("Unknown audio codec ", *forceAudioCodecPtr, ", expected one of: ", strings.Join(ffmpegutil.AUDIO_CODECS, ", "), "\n")
+
+		flag.Usage()
+		os.Exit(1)
+	} else if !contains(ffmpegutil.STILL_FORMATS, *stillFormatPtr) {
+		println("Unknown still format ", *stillFormatPtr, ", expected one of: ", strings.Join(ffmpegutil.STILL_FORMATS, ", "), "\n")
+
+		flag.Usage()
+		os.Exit(1)
+	} else if *segmentDurationPtr != 0 && *segmentDurationPtr < time.Second {
+		// Outputs are named with a start timecode to the second, so shorter segments would overwrite each other
+		println("Segment duration must be at least 1s!\n")
+
+		flag.Usage()
+		os.Exit(1)
+	} else if *keyframesOnlyPtr && (*forceRatePtr > 0 || *segmentDurationPtr > 0) {
+		println("Cannot force the framerate or split into segments when extracting only keyframes!\n")
+
+		flag.Usage()
+		os.Exit(1)
+	} else if len(*dumpTimingPtr) > 0 && !*remuxPtr {
+		println("Dumping timing requires MP4 output!\n")
+
+		flag.Usage()
+		os.Exit(1)
+	} else if err := checkFilename(*baseNamePtr); err != nil {
+		println("Invalid -base-name: ", err.Error(), "\n")
+
+		flag.Usage()
+		os.Exit(1)
+	} else if *burnTimestampPtr && (!*remuxPtr || !*includeVideoPtr) {
+		println("Burning in timestamps requires both video and MP4 output!\n")
+
+		flag.Usage()
+		os.Exit(1)
+	} else if *joinPtr && (*partitionPtr >= 0 || *segmentDurationPtr > 0 || len(*frameAtPtr) > 0) {
+		println("Cannot select a partition, split into segments or extract a still when joining files!\n")
+
+		flag.Usage()
+		os.Exit(1)
+	}
+
+	var frameAt time.Time
+	if len(*frameAtPtr) > 0 {
+		var err error
+		if frameAt, err = time.Parse(time.RFC3339, *frameAtPtr); err != nil {
+			println("Invalid -frame-at time, expected RFC3339 (e.g. 2020-08-14T17:17:48Z): ", err.Error(), "\n")
+
+			flag.Usage()
+			os.Exit(1)
+		}
+	}
+
+	timezone, err := parseTimezone(*timezonePtr)
+	if err != nil {
+		println("Invalid -timezone: ", err.Error(), "\n")
+
+		flag.Usage()
+		os.Exit(1)
+	}
+
+	// Check the output folder up front, rather than failing once the first partition is written
+	if strings.TrimSuffix(*outputFolder, "/") != "SRC-FOLDER" {
+		if stat, err := os.Stat(*outputFolder); os.IsNotExist(err) && *mkdirPtr {
+			if err := os.MkdirAll(*outputFolder, 0755); err != nil {
+				log.Fatal("Could not create output folder ", *outputFolder, ": ", err)
+			}
+		} else if os.IsNotExist(err) {
+			println("Output folder does not exist (use -mkdir to create it): ", *outputFolder, "\n")
+
+			os.Exit(1)
+		} else if err != nil {
+			log.Fatal("Could not access output folder ", *outputFolder, ": ", err)
+		} else if !stat.IsDir() {
+			println("Output folder is not a directory: ", *outputFolder, "\n")
+
+			os.Exit(1)
+		}
+	}
+
+	if len(*logFilePtr) > 0 {
+		logFile, err := os.OpenFile(*logFilePtr, os.O_CREATE|os.O_WRONLY|os.O_APPEND, 0644)
+		if err != nil {
+			log.Fatal("Could not open log file ", *logFilePtr, ": ", err)
+		}
+
+		defer logFile.Close()
+
+		log.SetOutput(io.MultiWriter(os.Stderr, logFile))
+	}
+
+	if *burnTimestampPtr {
+		log.Println("Warning: burning in timestamps decodes and re-encodes all video, this is far slower and more CPU intensive than a remux")
+	}
+
+	config := RemuxConfig{
+		ExtractAudio: *includeAudioPtr,
+		ExtractVideo: *includeVideoPtr,
+		ForceRate:    *forceRatePtr,
+		MaxFps:       *maxFpsPtr,
+
+		StartAtKeyframe: *startAtKeyframePtr,
+		SegmentDuration: *segmentDurationPtr,
+		PartitionIndex:  *partitionPtr,
+		Join:            *joinPtr,
+		VideoClockRate:  *videoClockRatePtr,
+		KeyframesOnly:   *keyframesOnlyPtr,
+
+		CreateMP4:    *remuxPtr,
+		KeepTemp:     *keepTempPtr,
+		OutputFolder: *outputFolder,
+		BaseName:     *baseNamePtr,
+		Timezone:     timezone,
+		TimeFormat:   *timeFormatPtr,
+		Overwrite:    *overwritePtr,
+		Verify:       *verifyPtr,
+		FailFast:     *failFastPtr,
+		Checksums:    *checksumsPtr,
+		DumpTiming:   *dumpTimingPtr,
+		Mux: ffmpegutil.MuxOptions{
+			ProbeSize:       *probeSizePtr,
+			AnalyzeDuration: *analyzeDurationPtr,
+			BurnTimestamp:   *burnTimestampPtr,
+			UseAverageRate:  *averageRatePtr,
+			LogLevel:        *ffmpegLogLevelPtr,
+			VideoCodec:      *forceVideoCodecPtr,
+			AudioCodec:      *forceAudioCodecPtr,
+			Threads:         *threadsPtr,
+			AudioDelay:      time.Duration(*audioDelayPtr) * time.Millisecond,
+		},
+		Still: ffmpegutil.StillOptions{
+			MaxWidth:   *stillMaxWidthPtr,
+			MaxHeight:  *stillMaxHeightPtr,
+			Format:     *stillFormatPtr,
+			VideoCodec: *forceVideoCodecPtr,
+		},
+	}
+
+	if !frameAt.IsZero() {
+		StillCLI(flag.Args(), frameAt, config)
+
+		os.Exit(0)
+	}
+
+	reports := RemuxCLI(flag.Args(), config)
+
+	if len(*reportPtr) > 0 {
+		if err := writeReport(*reportPtr, reports); err != nil {
+			log.Fatal("Could not write report ", *reportPtr, ": ", err)
+		}
 	}
 
-	RemuxCLI(flag.Args(), *includeAudioPtr, *includeVideoPtr, *forceRatePtr, *remuxPtr, *outputFolder)
+	for _, report := range reports {
+		if !report.Success {
+			os.Exit(1)
+		}
+	}
 }
 
-// Takes parsed commandline args and performs the remux tasks across the set of input files
-func RemuxCLI(files []string, extractAudio bool, extractVideo bool, forceRate int, createMP4 bool, outputFolder string) {
-	for _, ubvFile := range files {
-		log.Println("Analysing ", ubvFile)
-		info := ubv.Analyse(ubvFile, extractAudio)
+// Options for RemuxCLI, populated from the commandline
+type RemuxConfig struct {
+	ExtractAudio bool
+	ExtractVideo bool
+
+	// If non-zero, overrides the detected video framerate
+	ForceRate int
+
+	// If non-zero, detected video framerates above this are treated as implausible and corrected
+	MaxFps int
+
+	// If true, video frames preceding the first keyframe of each partition are dropped
+	StartAtKeyframe bool
+
+	// If non-zero, each partition is split into separate outputs of (at least) this duration
+	SegmentDuration time.Duration
+
+	// If non-negative, only the partition with this index is extracted
+	PartitionIndex int
+
+	// If true, the input files are joined into outputs spanning them all (see remuxJoined)
+	Join bool
+
+	// If non-zero, the timebase assumed for video frames with an invalid timebase
+	VideoClockRate int64
 
-		log.Printf("\n\nAnalysis complete!\n")
-		if len(info.Partitions) > 0 {
-			log.Printf("First Partition:")
-			log.Printf("\tTracks: %d", len(info.Partitions[0].Tracks))
-			log.Printf("\tFrames: %d", len(info.Partitions[0].Frames))
-			log.Printf("\tStart Timecode: %s", info.Partitions[0].Tracks[7].StartTimecode.Format(time.RFC3339))
+	// If true, only video keyframes are extracted
+	KeyframesOnly bool
+
+	CreateMP4 bool
+
+	// If true, the raw bitstreams an MP4 is created from are left on disk afterwards
+	KeepTemp bool
+
+	// The folder to write output files to, or "SRC-FOLDER" to write alongside the .ubv
+	OutputFolder string
+
+	// If non-empty, the prefix for output filenames (otherwise derived from the .ubv filename)
+	BaseName string
+
+	// The timezone the start timecode in output filenames is given in
+	Timezone *time.Location
+
+	// If non-empty, the Go time layout for the start timecode in output filenames (otherwise RFC3339, with : replaced)
+	TimeFormat string
+
+	// If false, partitions whose output files already exist are skipped rather than replaced
+	Overwrite bool
+
+	// If true, each MP4 is re-opened with FFprobe after writing and deleted if it doesn't look right
+	Verify bool
+
+	// If true, processing stops at the first error (in any file or partition)
+	FailFast bool
+
+	// If true, SHA-256 digests of the essence of each partition (and of the whole file) are logged
+	Checksums bool
+
+	// If non-empty, a CSV file to write the expected timing of every frame muxed to
+	DumpTiming string
+
+	// Options passed through to FFmpeg when creating MP4s
+	Mux ffmpegutil.MuxOptions
+
+	// Options for stills extracted with -frame-at
+	Still ffmpegutil.StillOptions
+}
+
+// Takes parsed commandline args and performs the remux tasks across the set of input files, returning a report for
+// each file processed
+func RemuxCLI(files []string, config RemuxConfig) []FileReport {
+	// Frame buffer shared across every partition demuxed, growing as needed
+	var frameBuffer []byte
+
+	var reports []FileReport
+
+	// The output basenames written so far this run, and the partition each was written for
+	claimed := make(map[string]string)
+
+	var timing *ffmpegutil.TimingWriter
+	if len(config.DumpTiming) > 0 {
+		timingFile, err := os.Create(config.DumpTiming)
+		if err != nil {
+			log.Fatal("Could not create timing file ", config.DumpTiming, ": ", err)
 		}
 
-		log.Printf("\n\nExtracting %d partitions", len(info.Partitions))
+		defer timingFile.Close()
 
-		// Optionally apply the user's forced framerate
-		if forceRate > 0 {
-			log.Println("\nFramerate forced by user instruction: using ", forceRate, " fps")
-			for _, partition := range info.Partitions {
-				for _, track := range partition.Tracks {
-					if track.IsVideo {
-						track.Rate = forceRate
-					}
-				}
+		if timing, err = ffmpegutil.NewTimingWriter(timingFile); err != nil {
+			log.Fatal("Could not write to timing file ", config.DumpTiming, ": ", err)
+		}
+	}
+
+	if config.Join {
+		// All the files are reported together, as the outputs span them
+		report := FileReport{Input: files[0], Inputs: files, StartTime: time.Now()}
+
+		frameBuffer = remuxJoined(files, config, &report, timing, claimed, frameBuffer)
+
+		report.Success = len(report.Errors) == 0
+		report.DurationSeconds = time.Since(report.StartTime).Seconds()
+		reports = append(reports, report)
+	} else {
+		for _, ubvFile := range files {
+			report := FileReport{Input: ubvFile, StartTime: time.Now()}
+
+			frameBuffer = remuxFile(ubvFile, config, &report, timing, claimed, frameBuffer)
+
+			report.Success = len(report.Errors) == 0
+			report.DurationSeconds = time.Since(report.StartTime).Seconds()
+			reports = append(reports, report)
+
+			if config.FailFast && !report.Success {
+				break
 			}
 		}
+	}
+
+	return reports
+}
+
+// Remuxes the partitions of a single .ubv, recording its outputs and errors in report. A panic while processing the
+// file (e.g. from an unexpectedly malformed recording) is recorded as an error rather than aborting the whole batch.
+// Returns the (possibly grown) frame buffer for reuse with the next file
+func remuxFile(ubvFile string, config RemuxConfig, report *FileReport, timing *ffmpegutil.TimingWriter, claimed map[string]string, frameBuffer []byte) (buffer []byte) {
+	buffer = frameBuffer
+
+	// The outputs of the partition being processed, which are incomplete (so deleted on failure) until it finishes
+	var partialOutputs []string
+
+	defer func() {
+		if r := recover(); r != nil {
+			log.Printf("Error: unexpected failure processing %s: %v\n%s", ubvFile, r, debug.Stack())
+			report.Errors = append(report.Errors, fmt.Sprint("unexpected failure: ", r))
+
+			removeFiles(partialOutputs...)
+		}
+	}()
+
+	switch kind := ubv.ClassifyRecording(ubvFile); kind {
+	case ubv.RECORDING_LOW_RESOLUTION_ROTATING, ubv.RECORDING_TIMELAPSE:
+		log.Printf("Warning: %s looks like a %s recording, the full resolution recording is the _0_rotating_ file", ubvFile, kind)
+	}
+
+	log.Println("Analysing ", ubvFile)
+	info, err := ubv.Analyse(ubvFile, ubv.ParseOptions{
+		IncludeAudio:    config.ExtractAudio,
+		StartAtKeyframe: config.StartAtKeyframe,
+		VideoClockRate:  config.VideoClockRate,
+	})
+	if err != nil {
+		log.Println("Error: could not analyse ", ubvFile, ": ", err)
+		report.Errors = append(report.Errors, "analysis failed: "+err.Error())
+
+		return buffer
+	}
+
+	// N.B. computed before anything is dropped from the analysis, so it doesn't depend on what is extracted
+	if config.Checksums {
+		if digest, err := digestFile(ubvFile, info, config); err != nil {
+			log.Println("Error: failed to compute checksum of ", ubvFile, ": ", err)
+			report.Errors = append(report.Errors, "failed to compute checksum: "+err.Error())
+		} else {
+			log.Printf("%s essence SHA-256: %x", ubvFile, digest)
+		}
+	}
+
+	log.Printf("\n\nAnalysis complete!\n")
+	if len(info.Partitions) > 0 {
+		log.Printf("First Partition:")
+		log.Printf("\tTracks: %d", len(info.Partitions[0].Tracks))
+		log.Printf("\tFrames: %d", len(info.Partitions[0].Frames))
+		log.Printf("\tStart Timecode: %s", info.Partitions[0].StartTimecode.Format(time.RFC3339))
+
+		if videoTrack, ok := info.Partitions[0].Tracks[7]; ok {
+			log.Printf("\tKeyframes: %d (GOP length average %.1f, max %d frames)", videoTrack.KeyframeCount, videoTrack.AverageGopLength(), videoTrack.MaxGopLength)
+		}
+	}
+
+	if config.PartitionIndex >= len(info.Partitions) {
+		log.Printf("Error: partition %d requested but %s only has %d partitions", config.PartitionIndex, ubvFile, len(info.Partitions))
+		report.Errors = append(report.Errors, fmt.Sprintf("partition %d requested but only %d partitions present", config.PartitionIndex, len(info.Partitions)))
+	} else if config.PartitionIndex >= 0 {
+		log.Printf("\n\nExtracting partition %d of %d", config.PartitionIndex, len(info.Partitions))
+	} else {
+		log.Printf("\n\nExtracting %d partitions", len(info.Partitions))
+	}
+
+	adjustPartitions(info.Partitions, &config)
+
+	partitions := info.Partitions
+
+	// Optionally break each partition up into multiple outputs
+	if config.SegmentDuration > 0 {
+		partitions = nil
 
 		for _, partition := range info.Partitions {
-			var videoFile string
-			var audioFile string
-			var mp4 string
-			{
-				outputFolder := strings.TrimSuffix(outputFolder, "/")
-
-				if outputFolder == "SRC-FOLDER" {
-					outputFolder = path.Dir(info.Filename)
-				}
+			segments := ubv.SplitPartition(partition, config.SegmentDuration)
 
-				// Strip the unixtime from the filename, we'll replace with the start timecode of the partition
-				baseFilename := strings.TrimSuffix(path.Base(ubvFile), path.Ext(ubvFile))
+			if len(segments) > 1 {
+				log.Printf("Splitting partition %d into %d segments", partition.Index, len(segments))
+			}
 
-				// If the filename contains underscores, assume it's a Unifi Protect Filename
-				// and drop the final component.
-				if strings.Contains(baseFilename, "_") {
-					baseFilename = baseFilename[0:strings.LastIndex(baseFilename, "_")]
-				}
+			partitions = append(partitions, segments...)
+		}
+	}
 
-				basename := outputFolder + "/" + baseFilename + "_" + strings.ReplaceAll(getStartTimecode(partition).Format(time.RFC3339), ":", ".")
+	// Open the .ubv once and share the handle across all partitions
+	ubvReader, err := demux.OpenUbv(ubvFile)
+	if err != nil {
+		log.Println("Error: could not open ", ubvFile, ": ", err)
+		report.Errors = append(report.Errors, "could not open: "+err.Error())
 
-				if extractVideo && partition.VideoTrackCount > 0 {
-					videoFile = basename + ".h264"
-				}
+		return buffer
+	}
 
-				if extractAudio && partition.AudioTrackCount > 0 {
-					audioFile = basename + ".aac"
-				}
+	defer ubvReader.Close()
+
+	for _, partition := range partitions {
+		if config.PartitionIndex >= 0 && partition.Index != config.PartitionIndex {
+			continue
+		}
+
+		videoFile, audioFile, mp4, err := partitionOutputs(ubvFile, partition, config, claimed)
+		if err != nil {
+			log.Println("Error: ", err)
+			report.Errors = append(report.Errors, err.Error())
+			continue
+		} else if existing := existingOutput(videoFile, audioFile, mp4, config); len(existing) > 0 {
+			log.Println("Skipping partition ", partition.Name(), ", output already exists: ", existing)
+			continue
+		}
+
+		if len(videoFile) > 0 && partition.Tracks[7].KeyframeCount == 0 {
+			log.Printf("Warning: partition %s has no video keyframes, so its video can't be decoded and FFmpeg will likely fail to find its codec parameters. Please report the file", partition.Name())
+		}
+
+		// N.B. only AAC has a header to read the parameters from
+		if len(audioFile) > 0 && audioExtension(config) == "aac" {
+			if params, err := partition.AudioParams(ubvReader); err != nil {
+				log.Println("Warning: could not read audio parameters for partition ", partition.Name(), ": ", err)
+			} else {
+				log.Printf("Partition %s audio: %s", partition.Name(), params)
 
-				if createMP4 {
-					mp4 = basename + ".mp4"
+				if params.SampleRate != partition.Tracks[1000].Rate {
+					log.Printf("Warning: audio sample rate %d Hz differs from the track timebase %d", params.SampleRate, partition.Tracks[1000].Rate)
 				}
 			}
+		}
+
+		partialOutputs = partialFiles(videoFile, audioFile, mp4, config)
+
+		if buffer, err = remuxPartition(ubvReader, partition, videoFile, audioFile, mp4, config, report, timing, buffer); err != nil {
+			log.Println("Error: failed to remux partition ", partition.Name(), " of ", ubvFile, ": ", err)
+			report.Errors = append(report.Errors, fmt.Sprintf("partition %s: %v", partition.Name(), err))
+
+			removeFiles(partialOutputs...)
+		}
+
+		partialOutputs = nil
+
+		if config.FailFast && len(report.Errors) > 0 {
+			log.Println("Stopping at first failure (-fail-fast)")
+			break
+		}
+	}
+
+	logFileSummary(info, len(report.Outputs))
+
+	return buffer
+}
+
+// Works out the raw bitstream and MP4 files to write a partition of ubvFile to (any may be empty, if not being written),
+// claiming their basename. Fails if the basename is invalid or was already claimed by an earlier partition this run
+func partitionOutputs(ubvFile string, partition *ubv.UbvPartition, config RemuxConfig, claimed map[string]string) (videoFile string, audioFile string, mp4 string, err error) {
+	basename, err := outputBasename(ubvFile, getStartTimecode(partition), config)
+	if err != nil {
+		return "", "", "", err
+	}
+
+	// Two partitions mapping to the same output must be an error, not silently skipped as already existing
+	if err := claimOutput(claimed, basename, fmt.Sprintf("partition %s of %s", partition.Name(), ubvFile)); err != nil {
+		return "", "", "", err
+	}
+
+	if config.ExtractVideo && partition.VideoTrackCount > 0 {
+		videoFile = basename + "." + videoExtension(config)
+	}
+
+	// N.B. a partition without audio is muxed video only, rather than with an empty audio stream
+	if config.ExtractAudio && partition.AudioTrackCount > 0 {
+		audioFile = basename + "." + audioExtension(config)
+	} else if config.ExtractAudio {
+		log.Printf("Partition %s has no audio, output will be video only", partition.Name())
+	}
+
+	if config.CreateMP4 {
+		mp4 = basename + ".mp4"
+	}
+
+	return videoFile, audioFile, mp4, nil
+}
+
+// Returns the first of a partition's outputs that already exists (unless overwriting), meaning the partition should be
+// skipped. N.B. outputs written earlier in this run are claimed by partitionOutputs, so anything existing predates it
+func existingOutput(videoFile string, audioFile string, mp4 string, config RemuxConfig) string {
+	if config.Overwrite {
+		return ""
+	}
+
+	// N.B. when creating an MP4 the raw bitstreams are only temporary, so don't count as existing output
+	if config.CreateMP4 {
+		return firstExistingFile(mp4)
+	}
+
+	return firstExistingFile(videoFile, audioFile)
+}
+
+// The files to delete if writing a partition's outputs fails part way. N.B. with -keep-temp the raw bitstreams are kept
+// even if the partition fails, since they help diagnose why
+func partialFiles(videoFile string, audioFile string, mp4 string, config RemuxConfig) []string {
+	files := []string{mp4 + ffmpegutil.PARTIAL_SUFFIX}
+	if !config.CreateMP4 || !config.KeepTemp {
+		files = append(files, videoFile, audioFile)
+	}
+
+	return files
+}
+
+// Demuxes a partition to the given raw bitstream files (either of which may be empty, leaving that track out) and, if
+// creating MP4s, muxes those into mp4. Outputs are added to the report, and the (possibly grown) frame buffer returned
+func remuxPartition(ubvReader *os.File, partition *ubv.UbvPartition, videoFile string, audioFile string, mp4 string, config RemuxConfig, report *FileReport, timing *ffmpegutil.TimingWriter, buffer []byte) ([]byte, error) {
+	buffer, err := demux.DemuxSinglePartitionToNewFiles(ubvReader, videoFile, audioFile, videoExtension(config), partition, buffer)
+	if err != nil {
+		return buffer, err
+	}
+
+	if config.Checksums {
+		digest, err := ubv.DigestPartition(ubvReader, partition)
+		if err != nil {
+			return buffer, fmt.Errorf("failed to compute checksums: %v", err)
+		}
+
+		var digests []string
+		if digest.Video != nil {
+			digests = append(digests, fmt.Sprintf("video %x", digest.Video))
+		}
+		if digest.Audio != nil {
+			digests = append(digests, fmt.Sprintf("audio %x", digest.Audio))
+		}
+
+		log.Printf("Partition %s essence SHA-256: %s", partition.Name(), strings.Join(digests, ", "))
+	}
+
+	return buffer, muxPartition(partition, videoFile, audioFile, mp4, config, report, timing)
+}
+
+// If creating MP4s, muxes a partition's raw bitstream files (either of which may be empty, leaving that track out) into
+// mp4, verifying it and writing its timing if requested. Outputs are added to the report
+func muxPartition(partition *ubv.UbvPartition, videoFile string, audioFile string, mp4 string, config RemuxConfig, report *FileReport, timing *ffmpegutil.TimingWriter) error {
+	if !config.CreateMP4 {
+		if len(videoFile) > 0 {
+			report.Outputs = append(report.Outputs, videoFile)
+		}
+		if len(audioFile) > 0 {
+			report.Outputs = append(report.Outputs, audioFile)
+		}
+
+		return nil
+	}
+
+	log.Println("\nWriting MP4 ", mp4, "...")
+
+	// Spawn FFmpeg to remux
+	// TODO: could we generate an MP4 directly? Would require some analysis of the input bitstreams to build MOOV
+	if err := ffmpegutil.MuxAudioAndVideo(partition, videoFile, audioFile, mp4, config.Mux); err != nil {
+		return err
+	}
+
+	// N.B. the mux is skipped (and no MP4 written) if the partition has no frames
+	if _, err := os.Stat(mp4); err == nil {
+		if config.Verify {
+			if err := ffmpegutil.VerifyMP4(partition, len(videoFile) > 0, len(audioFile) > 0, mp4, config.Mux); err != nil {
+				log.Println("Error: MP4 verification failed, deleting ", mp4, ": ", err)
+				removeFiles(mp4)
+
+				return fmt.Errorf("verification of %s failed: %v", mp4, err)
+			}
+
+			log.Println("Verified ", mp4)
+		}
+
+		report.Outputs = append(report.Outputs, mp4)
+
+		if timing != nil {
+			if err := timing.WritePartition(partition, len(videoFile) > 0, len(audioFile) > 0, mp4, config.Mux); err != nil {
+				return fmt.Errorf("could not write to timing file %s: %v", config.DumpTiming, err)
+			}
+		}
+	}
 
-			demux.DemuxSinglePartitionToNewFiles(ubvFile, videoFile, audioFile, partition)
+	// Delete the raw bitstreams (unless the user wants to inspect them)
+	if !config.KeepTemp {
+		removeFiles(videoFile, audioFile)
+	}
 
-			if createMP4 {
-				log.Println("\nWriting MP4 ", mp4, "...")
+	return nil
+}
 
-				// Spawn FFmpeg to remux
-				// TODO: could we generate an MP4 directly? Would require some analysis of the input bitstreams to build MOOV
-				ffmpegutil.MuxAudioAndVideo(partition, videoFile, audioFile, mp4)
+// Applies the framerate options (-force-rate, or otherwise the detected rate sanity check) and -keyframes-only to the
+// analysed partitions, updating config's mux options to match
+func adjustPartitions(partitions []*ubv.UbvPartition, config *RemuxConfig) {
+	// Optionally apply the user's forced framerate
+	if config.ForceRate > 0 {
+		log.Println("\nFramerate forced by user instruction: using ", config.ForceRate, " fps")
+		config.Mux.UseAverageRate = false
 
-				// Delete
-				if len(videoFile) > 0 {
-					if err := os.Remove(videoFile); err != nil {
-						log.Println("Warning: could not delete ", videoFile+": ", err)
-					}
+		for _, partition := range partitions {
+			for _, track := range partition.Tracks {
+				if track.IsVideo {
+					track.Rate = config.ForceRate
 				}
-				if len(audioFile) > 0 {
-					if err := os.Remove(audioFile); err != nil {
-						log.Println("Warning: could not delete ", audioFile+": ", err)
-					}
+			}
+		}
+	} else {
+		// Otherwise sanity check the detected framerate
+		for _, partition := range partitions {
+			for _, track := range partition.Tracks {
+				if track.IsVideo {
+					correctVideoRate(partition, track, config.MaxFps)
 				}
 			}
 		}
 	}
+
+	// Optionally reduce the video to keyframes, muxed at the average keyframe rate so playback time is preserved
+	if config.KeyframesOnly {
+		log.Println("\nExtracting keyframes only")
+		config.Mux.UseAverageRate = true
+
+		for _, partition := range partitions {
+			partition.DropNonKeyframes()
+		}
+	}
+}
+
+// Computes the essence digest of every frame of a .ubv, independent of which partitions, tracks and frames are being
+// extracted. The analysis is re-run if info might be missing frames (audio, or video preceding the first keyframe)
+func digestFile(ubvFile string, info ubv.UbvFile, config RemuxConfig) ([]byte, error) {
+	if !config.ExtractAudio || config.StartAtKeyframe {
+		var err error
+		if info, err = ubv.Analyse(ubvFile, ubv.ParseOptions{IncludeAudio: true, VideoClockRate: config.VideoClockRate}); err != nil {
+			return nil, err
+		}
+	}
+
+	f, err := os.Open(ubvFile)
+	if err != nil {
+		return nil, err
+	}
+
+	defer f.Close()
+
+	return ubv.DigestFile(f, info)
+}
+
+// Returns the path (without extension) to write output for a .ubv to, ending with the given timecode. Fails if the
+// -time-format gives a timecode that isn't a valid filename
+func outputBasename(ubvFile string, timecode time.Time, config RemuxConfig) (string, error) {
+	outputFolder := strings.TrimSuffix(config.OutputFolder, "/")
+
+	if outputFolder == "SRC-FOLDER" {
+		outputFolder = path.Dir(ubvFile)
+	}
+
+	baseFilename := config.BaseName
+
+	if len(baseFilename) == 0 {
+		// Strip the unixtime from the filename, we'll replace with the start timecode of the partition
+		baseFilename = sanitiseFilename(strings.TrimSuffix(path.Base(ubvFile), path.Ext(ubvFile)))
+
+		// If the filename contains underscores, assume it's a Unifi Protect Filename
+		// and drop the final component.
+		if strings.Contains(baseFilename, "_") {
+			baseFilename = baseFilename[0:strings.LastIndex(baseFilename, "_")]
+		}
+	}
+
+	if config.Timezone != nil {
+		timecode = timecode.In(config.Timezone)
+	}
+
+	if len(config.TimeFormat) > 0 {
+		// N.B. checked per timecode, since whether a layout gives a valid filename can depend on the time and zone
+		formatted := timecode.Format(config.TimeFormat)
+		if err := checkFilename(formatted); err != nil {
+			return "", fmt.Errorf("invalid -time-format: %v", err)
+		}
+
+		return outputFolder + "/" + baseFilename + "_" + formatted, nil
+	}
+
+	return outputFolder + "/" + baseFilename + "_" + strings.ReplaceAll(timecode.Format(time.RFC3339), ":", "."), nil
+}
+
+// Characters that can't appear in a filename on Windows (which also covers the / separator on other platforms)
+const ILLEGAL_FILENAME_CHARS = "<>:\"/\\|?*"
+
+// Returns an error if name contains a character that can't appear in a filename on Windows
+func checkFilename(name string) error {
+	for _, c := range name {
+		if c < ' ' || strings.ContainsRune(ILLEGAL_FILENAME_CHARS, c) {
+			return fmt.Errorf("%q contains %q, which is not allowed in filenames", name, c)
+		}
+	}
+
+	return nil
+}
+
+// Replaces any characters that can't appear in a filename on Windows with _
+func sanitiseFilename(name string) string {
+	return strings.Map(func(c rune) rune {
+		if c < ' ' || strings.ContainsRune(ILLEGAL_FILENAME_CHARS, c) {
+			return '_'
+		}
+
+		return c
+	}, name)
+}
+
+// Parses a -timezone value: "utc", "local", a fixed offset such as "+02:00", or an IANA zone name
+func parseTimezone(value string) (*time.Location, error) {
+	switch strings.ToLower(value) {
+	case "utc", "z":
+		return time.UTC, nil
+	case "local":
+		return time.Local, nil
+	}
+
+	if strings.HasPrefix(value, "+") || strings.HasPrefix(value, "-") {
+		offset, err := time.Parse("-07:00", value)
+		if err != nil {
+			return nil, fmt.Errorf("expected an offset like +02:00, got %s", value)
+		}
+
+		_, seconds := offset.Zone()
+
+		return time.FixedZone(value, seconds), nil
+	}
+
+	return time.LoadLocation(value)
+}
+
+// Logs totals across every partition of a file (the per-partition detail above only covers the first partition)
+func logFileSummary(info ubv.UbvFile, outputFiles int) {
+	var videoFrames int
+	var audioFrames int
+	var clockJumps int
+	var earliest time.Time
+	var latest time.Time
+
+	for _, partition := range info.Partitions {
+		for _, track := range partition.Tracks {
+			if track.IsVideo {
+				videoFrames += track.FrameCount
+			} else {
+				audioFrames += track.FrameCount
+			}
+
+			clockJumps += track.ClockJumps
+		}
+
+		if !partition.StartTimecode.IsZero() && (earliest.IsZero() || partition.StartTimecode.Before(earliest)) {
+			earliest = partition.StartTimecode
+		}
+		if partition.EndTimecode.After(latest) {
+			latest = partition.EndTimecode
+		}
+	}
+
+	log.Printf("\n\nCompleted %s", info.Filename)
+	log.Printf("\tPartitions: %d", len(info.Partitions))
+	log.Printf("\tOutput Files: %d", outputFiles)
+	log.Printf("\tVideo Frames: %d", videoFrames)
+	log.Printf("\tAudio Frames: %d", audioFrames)
+
+	if clockJumps > 0 {
+		log.Printf("\tClock Jumps: %d (timecodes may be unreliable)", clockJumps)
+	}
+
+	if !earliest.IsZero() {
+		log.Printf("\tSpan: %s to %s (%s)", earliest.Format(time.RFC3339), latest.Format(time.RFC3339), latest.Sub(earliest).Round(time.Second))
+	}
+}
+
+// How far (as a fraction) the detected framerate may stray from the median DTS spacing before it's replaced
+const RATE_TOLERANCE = 0.2
+
+// The detected framerate comes from the gap between the first two frames, which on a jittery stream can be tiny (giving
+// an absurd rate) or zero. If it's missing, above maxFps (if non-zero) or disagrees with the median DTS spacing by more
+// than RATE_TOLERANCE, replace it with the median rate (or failing that the average), limited to maxFps
+func correctVideoRate(partition *ubv.UbvPartition, track *ubv.UbvTrack, maxFps int) {
+	measured := partition.MedianRate(track.TrackNumber)
+	if measured <= 0 {
+		measured = track.AverageRate()
+	}
+
+	if measured <= 0 {
+		return
+	} else if track.Rate > 0 && (maxFps <= 0 || track.Rate <= maxFps) && math.Abs(float64(track.Rate)-measured) <= measured*RATE_TOLERANCE {
+		return
+	}
+
+	corrected := int(math.Round(measured))
+	if corrected < 1 {
+		corrected = 1
+	} else if maxFps > 0 && corrected > maxFps {
+		corrected = maxFps
+	}
+
+	log.Printf("Warning: partition %d detected framerate of %d fps looks wrong (measured %.2f fps, -max-fps is %d), using %d fps", partition.Index, track.Rate, measured, maxFps, corrected)
+
+	track.Rate = corrected
+}
+
+// Returns true if values includes value
+func contains(values []string, value string) bool {
+	for _, candidate := range values {
+		if candidate == value {
+			return true
+		}
+	}
+
+	return false
+}
+
+// Records that basename is being written for owner, returning an error if an earlier partition this run already
+// wrote to it (e.g. two partitions starting within the same second of a coarse -time-format)
+func claimOutput(claimed map[string]string, basename string, owner string) error {
+	if previous, ok := claimed[basename]; ok {
+		return fmt.Errorf("output %s for %s would overwrite the output for %s", basename, owner, previous)
+	}
+
+	claimed[basename] = owner
+
+	return nil
+}
+
+// The file extension (without a leading ".") for raw video bitstreams, which follows -force-video-codec if given
+func videoExtension(config RemuxConfig) string {
+	if len(config.Mux.VideoCodec) > 0 {
+		return config.Mux.VideoCodec
+	}
+
+	return ubv.FileExtensionForTrack(7)
+}
+
+// The file extension (without a leading ".") for raw audio bitstreams, which follows -force-audio-codec if given
+func audioExtension(config RemuxConfig) string {
+	if len(config.Mux.AudioCodec) > 0 {
+		return config.Mux.AudioCodec
+	}
+
+	return ubv.FileExtensionForTrack(1000)
+}
+
+// Returns the first of the (non-empty) paths that already exists, or "" if none do
+func firstExistingFile(files ...string) string {
+	for _, file := range files {
+		if len(file) > 0 {
+			if _, err := os.Stat(file); err == nil {
+				return file
+			}
+		}
+	}
+
+	return ""
+}
+
+// Deletes those of the (non-empty) paths that exist, logging any that can't be deleted
+func removeFiles(files ...string) {
+	for _, file := range files {
+		if len(file) > 0 {
+			if err := os.Remove(file); err != nil && !os.IsNotExist(err) {
+				log.Println("Warning: could not delete ", file+": ", err)
+			}
+		}
+	}
 }
 
 func getStartTimecode(partition *ubv.UbvPartition) time.Time {
 	for _, track := range partition.Tracks {
-		if partition.VideoTrackCount == 0 || track.IsVideo {
+		if (partition.VideoTrackCount == 0 || track.IsVideo) && !track.StartTimecode.IsZero() {
 			return track.StartTimecode
 		}
 	}
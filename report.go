@@ -0,0 +1,33 @@
+package main
+
+import (
+	"encoding/json"
+	"io/ioutil"
+	"time"
+)
+
+// The outcome of remuxing a single input file, written out by -report
+type FileReport struct {
+	Input   string   `json:"input"`
+	Success bool     `json:"success"`
+	Outputs []string `json:"outputs"`
+	Errors  []string `json:"errors,omitempty"`
+
+	// With -join, every input file joined (Input being the first given)
+	Inputs []string `json:"inputs,omitempty"`
+
+	StartTime       time.Time `json:"start_time"`
+	DurationSeconds float64   `json:"duration_seconds"`
+}
+
+// Writes the reports for every file processed as a JSON document
+func writeReport(reportFile string, reports []FileReport) error {
+	data, err := json.MarshalIndent(struct {
+		Files []FileReport `json:"files"`
+	}{reports}, "", "  ")
+	if err != nil {
+		return err
+	}
+
+	return ioutil.WriteFile(reportFile, data, 0644)
+}
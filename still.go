@@ -0,0 +1,87 @@
+package main
+
+import (
+	"log"
+	"time"
+	"ubvremux/demux"
+	"ubvremux/ffmpegutil"
+	"ubvremux/ubv"
+)
+
+// Writes a still image of the video frame displayed at the given wall-clock time, for each input file that covers it
+func StillCLI(files []string, timecode time.Time, config RemuxConfig) {
+	var frameBuffer []byte
+
+	for _, ubvFile := range files {
+		log.Println("Analysing ", ubvFile)
+		info, err := ubv.Analyse(ubvFile, ubv.ParseOptions{StartAtKeyframe: true, VideoClockRate: config.VideoClockRate})
+		if err != nil {
+			log.Println("Error: could not analyse ", ubvFile, ": ", err)
+			continue
+		}
+
+		var partition *ubv.UbvPartition
+		for _, candidate := range info.Partitions {
+			if candidate.VideoTrackCount > 0 && !timecode.Before(candidate.StartTimecode) && !timecode.After(candidate.EndTimecode) {
+				partition = candidate
+				break
+			}
+		}
+
+		if partition == nil {
+			log.Printf("No partition of %s covers %s", ubvFile, timecode.Format(time.RFC3339))
+			continue
+		}
+
+		keyframeIndex := partition.KeyframeBefore(timecode)
+		frameIndex := partition.FrameAt(timecode)
+
+		if keyframeIndex < 0 || frameIndex < 0 {
+			log.Printf("No decodable frame at %s in partition %d of %s", timecode.Format(time.RFC3339), partition.Index, ubvFile)
+			continue
+		}
+
+		// Only the GOP up to the requested frame needs decoding
+		gop := &ubv.UbvPartition{
+			Index:           partition.Index,
+			Tracks:          map[int]*ubv.UbvTrack{7: partition.Tracks[7]},
+			VideoTrackCount: 1,
+		}
+
+		for _, frame := range partition.Frames[keyframeIndex : frameIndex+1] {
+			if frame.TrackNumber == 7 {
+				gop.Frames = append(gop.Frames, frame)
+			}
+		}
+
+		gop.FrameCount = len(gop.Frames)
+
+		basename, err := outputBasename(ubvFile, timecode, config)
+		if err != nil {
+			log.Println("Error: ", err)
+			continue
+		}
+
+		videoFile := basename + ".still." + videoExtension(config)
+		imageFile := basename + "." + config.Still.FormatOrDefault()
+
+		ubvReader, err := demux.OpenUbv(ubvFile)
+		if err != nil {
+			log.Println("Error: could not open ", ubvFile, ": ", err)
+			continue
+		}
+
+		frameBuffer, err = demux.DemuxSinglePartitionToNewFiles(ubvReader, videoFile, "", videoExtension(config), gop, frameBuffer)
+		ubvReader.Close()
+
+		if err != nil {
+			log.Println("Error: could not extract frames from ", ubvFile, ": ", err)
+		} else if err := ffmpegutil.ExtractStill(videoFile, len(gop.Frames)-1, imageFile, config.Still); err != nil {
+			log.Println("Error: could not extract still from ", ubvFile, ": ", err)
+		} else {
+			log.Println("Wrote ", imageFile)
+		}
+
+		removeFiles(videoFile)
+	}
+}
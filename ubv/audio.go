@@ -0,0 +1,64 @@
+package ubv
+
+import (
+	"fmt"
+	"io"
+	"strconv"
+)
+
+// Sample rates indexed by the ADTS sampling_frequency_index field
+var adtsSampleRates = [...]int{96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350}
+
+// The parameters of an AAC audio track, as described by the ADTS header on each packet
+type AudioParams struct {
+	SampleRate int
+	Channels   int
+}
+
+// Describes the audio as e.g. "AAC 48kHz stereo"
+func (a AudioParams) String() string {
+	channels := strconv.Itoa(a.Channels) + " channels"
+	switch a.Channels {
+	case 1:
+		channels = "mono"
+	case 2:
+		channels = "stereo"
+	}
+
+	return "AAC " + strconv.FormatFloat(float64(a.SampleRate)/1000, 'f', -1, 64) + "kHz " + channels
+}
+
+// Reads the sample rate and channel count from the ADTS header at the start of an audio packet
+func ParseADTSHeader(data []byte) (AudioParams, error) {
+	if len(data) < 7 {
+		return AudioParams{}, fmt.Errorf("packet of %d bytes is too short for an ADTS header", len(data))
+	} else if data[0] != 0xFF || data[1]&0xF0 != 0xF0 {
+		return AudioParams{}, fmt.Errorf("packet does not start with an ADTS syncword")
+	}
+
+	frequencyIndex := int(data[2]>>2) & 0x0F
+	if frequencyIndex >= len(adtsSampleRates) {
+		return AudioParams{}, fmt.Errorf("unsupported ADTS sampling frequency index %d", frequencyIndex)
+	}
+
+	return AudioParams{
+		SampleRate: adtsSampleRates[frequencyIndex],
+		Channels:   int(data[2]&0x01)<<2 | int(data[3]>>6),
+	}, nil
+}
+
+// Reads the audio parameters from the first audio packet of the partition
+func (p *UbvPartition) AudioParams(r io.ReaderAt) (AudioParams, error) {
+	for _, frame := range p.Frames {
+		if frame.TrackNumber == 1000 {
+			data, err := ReadFrameData(r, frame)
+			if err != nil {
+				return AudioParams{}, err
+			}
+
+			return ParseADTSHeader(data)
+		}
+	}
+
+	return AudioParams{}, fmt.Errorf("partition %s has no audio", p.Name())
+}
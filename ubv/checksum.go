@@ -0,0 +1,67 @@
+package ubv
+
+import (
+	"crypto/sha256"
+	"io"
+)
+
+// SHA-256 digests of a partition's essence (the raw frame payloads, in frame order). Either is nil if the partition
+// has no frames of that kind
+type PartitionDigest struct {
+	Video []byte
+	Audio []byte
+}
+
+// Computes digests of a partition's video and audio essence, which identify the media independently of the .ubv
+// container structure
+func DigestPartition(r io.ReaderAt, partition *UbvPartition) (PartitionDigest, error) {
+	video := sha256.New()
+	audio := sha256.New()
+
+	var videoFrames int
+	var audioFrames int
+
+	for _, frame := range partition.Frames {
+		data, err := ReadFrameData(r, frame)
+		if err != nil {
+			return PartitionDigest{}, err
+		}
+
+		if frame.TrackNumber == 7 {
+			video.Write(data)
+			videoFrames++
+		} else {
+			audio.Write(data)
+			audioFrames++
+		}
+	}
+
+	var digest PartitionDigest
+	if videoFrames > 0 {
+		digest.Video = video.Sum(nil)
+	}
+	if audioFrames > 0 {
+		digest.Audio = audio.Sum(nil)
+	}
+
+	return digest, nil
+}
+
+// Computes a single digest of the essence of every frame of every partition of a file. This only identifies the
+// recording if the analysis kept every frame (i.e. included audio and didn't start at keyframes)
+func DigestFile(r io.ReaderAt, info UbvFile) ([]byte, error) {
+	digest := sha256.New()
+
+	for _, partition := range info.Partitions {
+		for _, frame := range partition.Frames {
+			data, err := ReadFrameData(r, frame)
+			if err != nil {
+				return nil, err
+			}
+
+			digest.Write(data)
+		}
+	}
+
+	return digest.Sum(nil), nil
+}
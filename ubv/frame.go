@@ -0,0 +1,191 @@
+package ubv
+
+import (
+	"encoding/binary"
+	"fmt"
+	"io"
+	"sort"
+	"time"
+)
+
+// The NAL unit types of a Sequence Parameter Set in H.264 and HEVC
+const (
+	H264_NAL_TYPE_SPS = 7
+	HEVC_NAL_TYPE_SPS = 33
+)
+
+// Reads the raw payload of a frame from the .ubv it was parsed from. For video this is a series of 4-byte
+// length-prefixed H.264 NALs (see NALUnits), for audio it is raw AAC
+func ReadFrameData(r io.ReaderAt, frame UbvFrame) ([]byte, error) {
+	data := make([]byte, frame.Size)
+
+	if _, err := r.ReadAt(data, int64(frame.Offset)); err != nil {
+		return nil, fmt.Errorf("failed to read %d bytes at %d: %v", frame.Size, frame.Offset, err)
+	}
+
+	return data, nil
+}
+
+// Reads the raw payload of the frame at the given index within the partition
+func (p *UbvPartition) ReadFrame(r io.ReaderAt, index int) ([]byte, error) {
+	if index < 0 || index >= len(p.Frames) {
+		return nil, fmt.Errorf("frame index %d out of range, partition %s has %d frames", index, p.Name(), len(p.Frames))
+	}
+
+	return ReadFrameData(r, p.Frames[index])
+}
+
+// Whether a NAL unit is a Sequence Parameter Set of the given video codec ("h264" or "hevc"). N.B. the codecs' NAL
+// headers differ (HEVC's type is bits 1-6 of a two-byte header), so the same NAL can be an SPS in one and a slice in
+// the other
+func IsSPS(nal []byte, codec string) bool {
+	if len(nal) == 0 {
+		return false
+	}
+
+	switch codec {
+	case "h264":
+		return nal[0]&0x1F == H264_NAL_TYPE_SPS
+	case "hevc":
+		return (nal[0]>>1)&0x3F == HEVC_NAL_TYPE_SPS
+	default:
+		return false
+	}
+}
+
+// Returns the first SPS of the partition's video, which carries its profile and resolution, reading the video as the
+// given codec ("h264" or "hevc"). Fails if none precedes the first keyframe, which suggests the video is another codec
+func (p *UbvPartition) FirstSPS(r io.ReaderAt, codec string) ([]byte, error) {
+	for _, frame := range p.Frames {
+		if frame.TrackNumber != 7 {
+			continue
+		}
+
+		data, err := ReadFrameData(r, frame)
+		if err != nil {
+			return nil, err
+		}
+
+		nals, err := NALUnits(data)
+		if err != nil {
+			return nil, err
+		}
+
+		for _, nal := range nals {
+			if IsSPS(nal, codec) {
+				return nal, nil
+			}
+		}
+
+		// The SPS is sent with (or before) each keyframe, so there's no point looking further
+		if frame.IsKeyframe {
+			break
+		}
+	}
+
+	return nil, fmt.Errorf("no %s SPS precedes the first keyframe of partition %s", codec, p.Name())
+}
+
+// Returns the index (within Frames) of the last video keyframe with a DTS at or before dts, or -1 if there isn't one.
+// This is the frame decoding must start from to display the frame at dts
+func (p *UbvPartition) KeyframeBeforeDts(dts int64) int {
+	// Index the keyframes on first use, so repeated seeks (e.g. scrubbing) are a binary search rather than a scan
+	if p.keyframes == nil {
+		p.keyframes = []int{}
+		for i, frame := range p.Frames {
+			if frame.TrackNumber == 7 && frame.IsKeyframe {
+				p.keyframes = append(p.keyframes, i)
+			}
+		}
+	}
+
+	// N.B. video frames are stored in decode order, so keyframe DTS values are increasing
+	i := sort.Search(len(p.keyframes), func(i int) bool {
+		return p.Frames[p.keyframes[i]].Dts > dts
+	})
+
+	if i == 0 {
+		return -1
+	}
+
+	return p.keyframes[i-1]
+}
+
+// Returns the index (within Frames) of the last video keyframe at or before the given wall-clock time, or -1 if there
+// isn't one
+func (p *UbvPartition) KeyframeBefore(timecode time.Time) int {
+	if dts, ok := p.dtsAt(timecode); ok {
+		return p.KeyframeBeforeDts(dts)
+	}
+
+	return -1
+}
+
+// Returns the index (within Frames) of the video frame displayed at the given wall-clock time (the last one at or before
+// it), or -1 if there isn't one
+func (p *UbvPartition) FrameAt(timecode time.Time) int {
+	dts, ok := p.dtsAt(timecode)
+	if !ok {
+		return -1
+	}
+
+	index := -1
+	for i, frame := range p.Frames {
+		if frame.TrackNumber == 7 {
+			if frame.Dts > dts {
+				break
+			}
+
+			index = i
+		}
+	}
+
+	return index
+}
+
+// Converts a wall-clock time to a video DTS, relative to the first video frame (which is taken to be at the video
+// track's start timecode)
+func (p *UbvPartition) dtsAt(timecode time.Time) (int64, bool) {
+	track, ok := p.Tracks[7]
+	if !ok || track.clockRate <= 0 {
+		return 0, false
+	}
+
+	for _, frame := range p.Frames {
+		if frame.TrackNumber == 7 {
+			offset := timecode.Sub(track.StartTimecode)
+
+			// N.B. whole seconds and the remainder are converted separately, as nanoseconds multiplied by the clock
+			// rate would overflow for offsets of more than a day
+			seconds := int64(offset / time.Second)
+			remainder := int64(offset % time.Second)
+
+			return frame.Dts + seconds*track.clockRate + remainder*track.clockRate/int64(time.Second), true
+		}
+	}
+
+	return 0, false
+}
+
+// Splits a video frame payload into its NAL units (without their length prefixes)
+func NALUnits(data []byte) ([][]byte, error) {
+	var nals [][]byte
+
+	for pos := 0; pos < len(data); {
+		if pos+4 > len(data) {
+			return nil, fmt.Errorf("truncated NAL length prefix at %d", pos)
+		}
+
+		nalSize := int(binary.BigEndian.Uint32(data[pos : pos+4]))
+		pos += 4
+
+		if nalSize > len(data)-pos {
+			return nil, fmt.Errorf("NAL of %d bytes at %d goes beyond frame size %d", nalSize, pos, len(data))
+		}
+
+		nals = append(nals, data[pos:pos+nalSize])
+		pos += nalSize
+	}
+
+	return nals, nil
+}
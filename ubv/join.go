@@ -0,0 +1,78 @@
+package ubv
+
+// Joins partitions (e.g. the last of one .ubv and the first of the next, where a recording was split across files)
+// into a single partition describing them as one continuous timeline, for muxing as one output. Frames keep their
+// offsets, so must still be read from the .ubv of the partition they came from. Each track's DTS and CTS are rebased
+// so its decode timeline carries on across the joins, spaced by the wall-clock between the partitions
+func JoinPartitions(partitions []*UbvPartition) *UbvPartition {
+	joined := &UbvPartition{
+		Index:  partitions[0].Index,
+		Tracks: make(map[int]*UbvTrack),
+	}
+
+	for _, partition := range partitions {
+		// The amount added to the DTS and CTS of each track's frames
+		offsets := make(map[int]int64)
+
+		for _, source := range partition.Tracks {
+			track, ok := joined.Tracks[source.TrackNumber]
+
+			if !ok {
+				copied := *source
+				joined.Tracks[source.TrackNumber] = &copied
+
+				if copied.IsVideo {
+					joined.VideoTrackCount++
+				} else {
+					joined.AudioTrackCount++
+				}
+
+				continue
+			}
+
+			offsets[source.TrackNumber] = rebaseDts(track, source)
+
+			track.FrameCount += source.FrameCount
+			track.KeyframeCount += source.KeyframeCount
+			track.ClockJumps += source.ClockJumps
+			track.syncedFrameCount += source.syncedFrameCount
+			track.gopFrames += source.gopFrames
+			track.lastDts = source.lastDts + offsets[source.TrackNumber]
+
+			if source.MaxGopLength > track.MaxGopLength {
+				track.MaxGopLength = source.MaxGopLength
+			}
+			if source.LastTimecode.After(track.LastTimecode) {
+				track.LastTimecode = source.LastTimecode
+			}
+		}
+
+		for _, frame := range partition.Frames {
+			frame.Dts += offsets[frame.TrackNumber]
+			frame.Cts += offsets[frame.TrackNumber]
+
+			joined.Frames = append(joined.Frames, frame)
+		}
+
+		joined.FrameCount += partition.FrameCount
+	}
+
+	setPartitionTimecodes(joined)
+
+	return joined
+}
+
+// The offset to add to the DTS of source's frames so they follow on from those already in track: as far after the
+// track's first frame as the wall-clock says, but never overlapping the frames already joined
+func rebaseDts(track *UbvTrack, source *UbvTrack) int64 {
+	start := track.firstDts
+	if !track.StartTimecode.IsZero() && !source.StartTimecode.IsZero() {
+		start += int64(source.StartTimecode.Sub(track.StartTimecode).Seconds() * float64(track.clockRate))
+	}
+
+	if start <= track.lastDts {
+		start = track.lastDts + 1
+	}
+
+	return start - source.firstDts
+}
@@ -0,0 +1,51 @@
+package ubv
+
+import (
+	"path"
+	"strings"
+)
+
+// The kind of recording a .ubv holds, as indicated by its filename
+type RecordingKind int
+
+const (
+	RECORDING_UNKNOWN RecordingKind = iota
+
+	// The main, full resolution recording (e.g. MAC_0_rotating_1597425468956.ubv)
+	RECORDING_FULL_RESOLUTION
+
+	// A reduced resolution copy of the main recording (e.g. MAC_2_rotating_1597425468956.ubv)
+	RECORDING_LOW_RESOLUTION_ROTATING
+
+	// A timelapse recording (e.g. MAC_0_timelapse_1597425468956.ubv)
+	RECORDING_TIMELAPSE
+)
+
+func (k RecordingKind) String() string {
+	switch k {
+	case RECORDING_FULL_RESOLUTION:
+		return "full resolution"
+	case RECORDING_LOW_RESOLUTION_ROTATING:
+		return "low resolution"
+	case RECORDING_TIMELAPSE:
+		return "timelapse"
+	default:
+		return "unknown"
+	}
+}
+
+// Works out what kind of recording a .ubv holds from the naming scheme Unifi Protect uses
+func ClassifyRecording(ubvFile string) RecordingKind {
+	baseFilename := path.Base(ubvFile)
+
+	switch {
+	case strings.Contains(baseFilename, "_timelapse_"):
+		return RECORDING_TIMELAPSE
+	case strings.Contains(baseFilename, "_2_rotating_"):
+		return RECORDING_LOW_RESOLUTION_ROTATING
+	case strings.Contains(baseFilename, "_0_rotating_"):
+		return RECORDING_FULL_RESOLUTION
+	default:
+		return RECORDING_UNKNOWN
+	}
+}
@@ -0,0 +1,102 @@
+package ubv
+
+import (
+	"time"
+)
+
+// Splits a partition into consecutive segments, each starting on a video keyframe (so it can be muxed as a standalone
+// MP4) once at least segmentDuration has elapsed since the start of the previous segment. Elapsed time is measured in
+// video frames at the track's rate (which is how the video is muxed), so segments line up with the output timeline
+// even if the wall-clock jitters. Audio stays with the video it's interleaved with. Partitions without video (or
+// without a known framerate) are returned unsplit
+func SplitPartition(partition *UbvPartition, segmentDuration time.Duration) []*UbvPartition {
+	videoTrack, ok := partition.Tracks[7]
+	if !ok || videoTrack.Rate <= 0 || segmentDuration <= 0 {
+		return []*UbvPartition{partition}
+	}
+
+	var segments []*UbvPartition
+	var current *UbvPartition
+
+	// Offsets (from the start of the partition) of the current segment and of the most recent video frame
+	var segmentOffset time.Duration
+	var position time.Duration
+
+	videoFrames := 0
+
+	for _, frame := range partition.Frames {
+		if frame.TrackNumber == 7 {
+			position = videoFrameOffset(videoFrames, videoTrack.Rate)
+
+			if current == nil || (frame.IsKeyframe && position-segmentOffset >= segmentDuration) {
+				current = nil
+				segmentOffset = position
+			}
+
+			videoFrames++
+		}
+
+		// N.B. any audio preceding the first video frame goes in the first segment
+		if current == nil {
+			current = &UbvPartition{
+				Index:  partition.Index,
+				Tracks: make(map[int]*UbvTrack),
+			}
+
+			segments = append(segments, current)
+		}
+
+		addFrameToSegment(current, partition.Tracks[frame.TrackNumber], frame, segmentOffset, position)
+	}
+
+	for i, segment := range segments {
+		if len(segments) > 1 {
+			segment.Segment = i + 1
+		}
+
+		setPartitionTimecodes(segment)
+	}
+
+	return segments
+}
+
+// The offset of a video frame from the start of its partition, when muxed at a fixed framerate
+func videoFrameOffset(index int, rate int) time.Duration {
+	return time.Duration(index) * time.Second / time.Duration(rate)
+}
+
+func addFrameToSegment(segment *UbvPartition, source *UbvTrack, frame UbvFrame, segmentOffset time.Duration, position time.Duration) {
+	track, ok := segment.Tracks[frame.TrackNumber]
+
+	if !ok {
+		track = &UbvTrack{
+			IsVideo:       source.IsVideo,
+			TrackNumber:   source.TrackNumber,
+			Rate:          source.Rate,
+			StartTimecode: offsetTimecode(source.StartTimecode, segmentOffset),
+		}
+
+		segment.Tracks[frame.TrackNumber] = track
+
+		if track.IsVideo {
+			segment.VideoTrackCount++
+		} else {
+			segment.AudioTrackCount++
+		}
+	}
+
+	track.LastTimecode = offsetTimecode(source.StartTimecode, position)
+
+	segment.FrameCount++
+	track.countFrame(frame)
+	segment.Frames = append(segment.Frames, frame)
+}
+
+// Adds an offset to a timecode, leaving unknown (zero) timecodes unknown
+func offsetTimecode(timecode time.Time, offset time.Duration) time.Time {
+	if timecode.IsZero() {
+		return timecode
+	}
+
+	return timecode.Add(offset)
+}
@@ -1,8 +1,13 @@
 package ubv
 
 import (
+	"fmt"
 	"log"
+	"math"
+	"path"
+	"sort"
 	"strconv"
+	"strings"
 	"time"
 )
 
@@ -18,6 +23,10 @@ const (
 	FIELD_OFFSET      = 3
 	FIELD_SIZE        = 4
 
+	//Decode and composition timestamps, in units of FIELD_WC_TBC. Composition timestamp is the presentation time
+	FIELD_DTS = 5
+	FIELD_CTS = 6
+
 	//WC field: wall-clock perhaps? value is UTC time since 1970, expressed in units of FIELD_WC_TBC. Divide by TBC to get fractional seconds.
 	FIELD_WC = 7
 
@@ -25,6 +34,12 @@ const (
 	FIELD_WC_TBC = 8
 
 	PROBE_FRAMES = 70
+
+	// Forward jumps in wall-clock between consecutive frames of a track larger than this are reported as clock adjustments
+	CLOCK_JUMP_TOLERANCE = 10 * time.Second
+
+	// The timebase assumed for video frames with an invalid (zero) timebase; the usual 90kHz video clock
+	DEFAULT_VIDEO_CLOCK_RATE = 90000
 )
 
 type UbvFrame struct {
@@ -32,6 +47,13 @@ type UbvFrame struct {
 	TrackNumber int
 	Offset      int
 	Size        int
+
+	// True if this is a keyframe (only ever set on video tracks)
+	IsKeyframe bool
+
+	// Decode and composition timestamps, in the track's timebase
+	Dts int64
+	Cts int64
 }
 
 type UbvTrack struct {
@@ -44,6 +66,12 @@ type UbvTrack struct {
 	// Number of frames (video) or packets (audio)
 	FrameCount int
 
+	// Number of keyframes (only ever non-zero for video)
+	KeyframeCount int
+
+	// The most frames seen in a single GOP (a keyframe and the frames following it up to the next keyframe)
+	MaxGopLength int
+
 	// The timebase of this track (number of samples every second)
 	// For video, the number of frames per second
 	// For audio, the number of samples (N.B. we do not index individual samples)
@@ -51,15 +79,49 @@ type UbvTrack struct {
 
 	// The date+time of the last frame in this partition
 	LastTimecode time.Time
+
+	// Number of times the wall-clock went backwards (or leapt forwards) between consecutive frames, typically because
+	// a later clock sync disagreed with an earlier one
+	ClockJumps int
+
+	// Number of frames seen with a valid wall-clock (frames preceding any clock sync have a WC of 0)
+	syncedFrameCount int
+
+	// The wall-clock of the first frame with a valid wall-clock, which the video rate is measured from (unlike
+	// StartTimecode this isn't back-dated to frames preceding the clock sync)
+	firstSyncTimecode time.Time
+
+	// The timebase of the most recent frame, and the number of frames whose timebase was invalid (so had to be assumed)
+	clockRate         int64
+	invalidClockRates int
+
+	// Frames from the first keyframe onwards, and frames in the current GOP
+	gopFrames int
+	gopLength int
+
+	// The DTS of the first and most recent frames, in units of clockRate
+	firstDts int64
+	lastDts  int64
 }
 
 type UbvPartition struct {
-	Index           int
+	Index int
+
+	// The 1-based number of this segment, if SplitPartition broke the partition into more than one (0 otherwise)
+	Segment int
+
 	FrameCount      int
 	Tracks          map[int]*UbvTrack
 	VideoTrackCount int
 	AudioTrackCount int
 	Frames          []UbvFrame
+
+	// The earliest start timecode and latest last timecode across all the partition's tracks
+	StartTimecode time.Time
+	EndTimecode   time.Time
+
+	// The indices (within Frames) of the video keyframes, built on first use by KeyframeBeforeDts
+	keyframes []int
 }
 
 type UbvFile struct {
@@ -68,21 +130,44 @@ type UbvFile struct {
 	Partitions []*UbvPartition
 }
 
-func extractTimecodeAndRate(fields []string, line string, track *UbvTrack) {
+func extractTimecodeAndRate(fields []string, line string, track *UbvTrack, videoClockRate int64) error {
 	var err error
 	var wc int64
 	var tbc int64
 
 	if wc, err = strconv.ParseInt(fields[FIELD_WC], 10, 64); err != nil {
-		log.Fatal("Error parsing field offset!", err)
+		return fmt.Errorf("error parsing wall-clock in line %q: %v", line, err)
 	}
 	if tbc, err = strconv.ParseInt(fields[FIELD_WC_TBC], 10, 64); err != nil {
-		log.Fatal("Error parsing frame size!", err)
+		return fmt.Errorf("error parsing timebase in line %q: %v", line, err)
 	}
 
-	// Bail if we encounter a TBC of 0, otherwise we'll have a divide by zeor
-	if tbc == 0 {
-		log.Fatal("Parsed TBC returned 0! ", tbc, " for line ", line)
+	// A TBC of 0 would give a divide by zero, so substitute a sensible timebase (bailing if there isn't one)
+	if tbc <= 0 {
+		assumed := assumedClockRate(track, videoClockRate)
+
+		if assumed <= 0 {
+			return fmt.Errorf("invalid timebase %d in line %q and no timebase could be assumed", tbc, line)
+		} else if track.invalidClockRates == 0 {
+			log.Printf("Warning: track %d has an invalid timebase of %d at frame %d, assuming %d", track.TrackNumber, tbc, track.FrameCount, assumed)
+		}
+
+		track.invalidClockRates++
+		tbc = assumed
+	} else if track.clockRate != 0 && tbc != track.clockRate {
+		log.Printf("Warning: track %d timebase changed from %d to %d at frame %d", track.TrackNumber, track.clockRate, tbc, track.FrameCount)
+	}
+
+	track.clockRate = tbc
+
+	// A WC of 0 means no clock sync has preceded this frame, so there's no wall-clock to take a timecode from
+	if wc == 0 {
+		if !track.IsVideo && track.Rate == 0 {
+			// Ubiquiti use the audio sample rate directly for audio packet tbc
+			track.Rate = int(tbc)
+		}
+
+		return nil
 	}
 
 	utcMillis := (wc * 1000) / tbc
@@ -91,24 +176,287 @@ func extractTimecodeAndRate(fields []string, line string, track *UbvTrack) {
 	utcNanosPart := (utcMillis % 1000) * 1000000
 	frameTimecode := time.Unix(utcSecondsPart, utcNanosPart)
 
+	if track.syncedFrameCount > 0 {
+		if jump := frameTimecode.Sub(track.LastTimecode); jump < 0 || jump > CLOCK_JUMP_TOLERANCE {
+			track.ClockJumps++
+			log.Printf("Warning: wall-clock on track %d jumped by %s at frame %d (clock sync adjusted mid-partition?)", track.TrackNumber, jump, track.FrameCount)
+		}
+	}
+
 	track.LastTimecode = frameTimecode
 
-	// Special-case 1st and 2nd frames (figuring out start timecode and framerate)
-	if track.FrameCount == 0 {
+	// Special-case 1st and 2nd synced frames (figuring out start timecode and framerate)
+	if track.syncedFrameCount == 0 {
 		log.Printf("First Frame timestamp %s", frameTimecode)
 		track.StartTimecode = frameTimecode
+		track.firstSyncTimecode = frameTimecode
+
+		// Back-date the start to the first frame, using how far its DTS precedes this one
+		if track.FrameCount > 0 {
+			track.StartTimecode = frameTimecode.Add(-dtsDuration(track.lastDts-track.firstDts, tbc))
+
+			log.Printf("Warning: %d frame(s) on track %d preceded the first clock sync, start timecode %s extrapolated from their DTS", track.FrameCount, track.TrackNumber, track.StartTimecode)
+		}
 
 		if !track.IsVideo {
 			// Ubiquiti use the audio sample rate directly for audio packet tbc
 			track.Rate = int(tbc)
 		}
-	} else if track.FrameCount == 1 {
+	} else if track.syncedFrameCount == 1 {
 		if track.IsVideo {
 			log.Printf("Second Frame timestamp %s", frameTimecode)
 
 			// Work out how long (expressed in tbc) has elapsed for this frame/packet
-			frameDuration := frameTimecode.Sub(track.StartTimecode)
-			track.Rate = int(1000 / frameDuration.Milliseconds())
+			// N.B. frames with identical timecodes leave the rate undetermined (0) rather than dividing by zero
+			frameDuration := frameTimecode.Sub(track.firstSyncTimecode)
+			if frameDuration.Milliseconds() > 0 {
+				track.Rate = int(1000 / frameDuration.Milliseconds())
+			}
+		}
+	}
+
+	track.syncedFrameCount++
+
+	return nil
+}
+
+// Counts a frame towards the track's frame, keyframe and GOP statistics
+func (t *UbvTrack) countFrame(frame UbvFrame) {
+	t.FrameCount++
+
+	if frame.IsKeyframe {
+		t.KeyframeCount++
+		t.gopLength = 0
+	}
+
+	// N.B. frames preceding the first keyframe are the tail of a GOP from an earlier partition, so aren't counted
+	if t.KeyframeCount > 0 {
+		t.gopFrames++
+		t.gopLength++
+
+		if t.gopLength > t.MaxGopLength {
+			t.MaxGopLength = t.gopLength
+		}
+	}
+}
+
+// The average number of frames in each GOP (0 if there are no keyframes)
+func (t *UbvTrack) AverageGopLength() float64 {
+	if t.KeyframeCount == 0 {
+		return 0
+	}
+
+	return float64(t.gopFrames) / float64(t.KeyframeCount)
+}
+
+// The timebase to use for a frame whose own timebase is invalid: the last valid one seen on the track, otherwise for
+// video the user's override or DEFAULT_VIDEO_CLOCK_RATE and for audio the sample rate (if known)
+func assumedClockRate(track *UbvTrack, videoClockRate int64) int64 {
+	if track.clockRate > 0 {
+		return track.clockRate
+	} else if !track.IsVideo {
+		return int64(track.Rate)
+	} else if videoClockRate > 0 {
+		return videoClockRate
+	} else {
+		return DEFAULT_VIDEO_CLOCK_RATE
+	}
+}
+
+// The number of frames per second implied by the median DTS spacing of a track's frames (0 if unknown). Unlike the
+// rate from the first two wall-clocks this isn't thrown off by a single jittery frame
+func (p *UbvPartition) MedianRate(trackNumber int) float64 {
+	track, ok := p.Tracks[trackNumber]
+	if !ok || track.clockRate <= 0 {
+		return 0
+	}
+
+	var deltas []int64
+	var previous *UbvFrame
+
+	for i := range p.Frames {
+		frame := &p.Frames[i]
+
+		if frame.TrackNumber != trackNumber {
+			continue
+		} else if previous != nil && frame.Dts > previous.Dts {
+			deltas = append(deltas, frame.Dts-previous.Dts)
+		}
+
+		previous = frame
+	}
+
+	if len(deltas) == 0 {
+		return 0
+	}
+
+	sort.Slice(deltas, func(i, j int) bool { return deltas[i] < deltas[j] })
+
+	return float64(track.clockRate) / float64(deltas[len(deltas)/2])
+}
+
+// The average number of frames per second across the track, based on its first and last timecodes (0 if unknown)
+func (t *UbvTrack) AverageRate() float64 {
+	duration := t.LastTimecode.Sub(t.StartTimecode).Seconds()
+
+	if t.FrameCount < 2 || duration <= 0 {
+		return 0
+	}
+
+	return float64(t.FrameCount-1) / duration
+}
+
+// The wall-clock time spanned by the partition
+func (p *UbvPartition) Duration() time.Duration {
+	return p.EndTimecode.Sub(p.StartTimecode)
+}
+
+// Sets the partition's start and end timecodes from those of its tracks
+func setPartitionTimecodes(partition *UbvPartition) {
+	for _, track := range partition.Tracks {
+		if !track.StartTimecode.IsZero() && (partition.StartTimecode.IsZero() || track.StartTimecode.Before(partition.StartTimecode)) {
+			partition.StartTimecode = track.StartTimecode
+		}
+		if track.LastTimecode.After(partition.EndTimecode) {
+			partition.EndTimecode = track.LastTimecode
 		}
 	}
 }
+
+// Identifies the partition in logs and other output: its index, followed by its segment number if it was split (e.g.
+// "3.2" for the second segment of partition 3)
+func (p *UbvPartition) Name() string {
+	if p.Segment > 0 {
+		return fmt.Sprintf("%d.%d", p.Index, p.Segment)
+	}
+
+	return strconv.Itoa(p.Index)
+}
+
+// Removes every video frame other than keyframes from the partition (leaving audio untouched), for a quick preview.
+// The video track keeps its timecodes, so muxing at its average rate spreads the keyframes across the original duration
+func (p *UbvPartition) DropNonKeyframes() {
+	frames := p.Frames[:0]
+	for _, frame := range p.Frames {
+		if frame.TrackNumber != 7 || frame.IsKeyframe {
+			frames = append(frames, frame)
+		}
+	}
+
+	p.FrameCount -= len(p.Frames) - len(frames)
+	p.Frames = frames
+	p.keyframes = nil
+
+	if track, ok := p.Tracks[7]; ok {
+		track.FrameCount = track.KeyframeCount
+	}
+}
+
+// Returns the file extension (without a leading ".") to use for a track's raw demuxed bitstream
+func FileExtensionForTrack(trackNumber int) string {
+	switch trackNumber {
+	case 7:
+		return "h264"
+	case 1000:
+		return "aac"
+	default:
+		return "bin"
+	}
+}
+
+// Gives timecodes to any track that never saw a clock sync (so has no wall-clock at all). Each is anchored on the first
+// clock sync of the same track number elsewhere in the file, offset by the DTS distance between the two. Failing that,
+// the first frame of the track number in the file is taken to be at the earliest wall-clock seen in the file or, if
+// there is none, the epoch millis embedded in the .ubv filename
+func fillMissingTimecodes(ubvFile string, partitions []*UbvPartition) {
+	type anchor struct {
+		timecode time.Time
+		dts      int64
+		source   string
+	}
+
+	anchors := make(map[int]anchor)
+	firstDts := make(map[int]int64)
+
+	var fallback time.Time
+	var fallbackSource string
+
+	for _, partition := range partitions {
+		for _, track := range partition.Tracks {
+			if _, ok := firstDts[track.TrackNumber]; !ok {
+				firstDts[track.TrackNumber] = track.firstDts
+			}
+
+			if track.StartTimecode.IsZero() {
+				continue
+			}
+
+			if _, ok := anchors[track.TrackNumber]; !ok {
+				anchors[track.TrackNumber] = anchor{track.StartTimecode, track.firstDts, fmt.Sprintf("first clock sync of track %d in partition %d", track.TrackNumber, partition.Index)}
+			}
+
+			if fallback.IsZero() || track.StartTimecode.Before(fallback) {
+				fallback = track.StartTimecode
+				fallbackSource = "first clock sync in file"
+			}
+		}
+	}
+
+	if fallback.IsZero() {
+		if timecode, ok := TimecodeFromFilename(ubvFile); ok {
+			fallback = timecode
+			fallbackSource = "filename timestamp"
+		}
+	}
+
+	for _, partition := range partitions {
+		for _, track := range partition.Tracks {
+			if !track.StartTimecode.IsZero() {
+				continue
+			}
+
+			from, ok := anchors[track.TrackNumber]
+			if !ok && fallback.IsZero() {
+				log.Printf("Warning: partition %d track %d has no clock sync and no timestamp could be derived from the filename", partition.Index, track.TrackNumber)
+				continue
+			} else if !ok {
+				from = anchor{fallback, firstDts[track.TrackNumber], fallbackSource}
+			}
+
+			track.StartTimecode = from.timecode.Add(dtsDuration(track.firstDts-from.dts, track.clockRate))
+			track.LastTimecode = track.StartTimecode.Add(dtsDuration(track.lastDts-track.firstDts, track.clockRate))
+
+			// With no wall-clock to measure, derive the framerate from the DTS spacing
+			if track.IsVideo && track.FrameCount > 1 && track.lastDts > track.firstDts {
+				track.Rate = int(math.Round(float64(track.FrameCount-1) * float64(track.clockRate) / float64(track.lastDts-track.firstDts)))
+			}
+
+			log.Printf("Warning: partition %d track %d has no clock sync, extrapolated a start timecode of %s from the %s (%s)", partition.Index, track.TrackNumber, track.StartTimecode.Format(time.RFC3339), from.source, from.timecode.Format(time.RFC3339))
+		}
+	}
+}
+
+// Converts a DTS difference (in units of clockRate) to a duration
+func dtsDuration(dts int64, clockRate int64) time.Duration {
+	if clockRate <= 0 {
+		return 0
+	}
+
+	return time.Duration(float64(dts) / float64(clockRate) * float64(time.Second))
+}
+
+// Extracts the epoch millis timestamp Unifi Protect puts at the end of .ubv filenames (e.g. MAC_0_rotating_1597425468956.ubv)
+func TimecodeFromFilename(ubvFile string) (time.Time, bool) {
+	baseFilename := strings.TrimSuffix(path.Base(ubvFile), path.Ext(ubvFile))
+
+	if !strings.Contains(baseFilename, "_") {
+		return time.Time{}, false
+	}
+
+	epochMillis, err := strconv.ParseInt(baseFilename[strings.LastIndex(baseFilename, "_")+1:], 10, 64)
+	if err != nil || epochMillis <= 0 {
+		return time.Time{}, false
+	}
+
+	return time.Unix(epochMillis/1000, (epochMillis%1000)*1000000), true
+}
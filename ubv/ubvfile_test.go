@@ -0,0 +1,461 @@
+package ubv
+
+import (
+	"fmt"
+	"strconv"
+	"strings"
+	"testing"
+	"time"
+)
+
+func TestFileExtensionForTrack(t *testing.T) {
+	cases := map[int]string{
+		7:    "h264",
+		1000: "aac",
+		1234: "bin",
+	}
+
+	for trackNumber, expected := range cases {
+		if actual := FileExtensionForTrack(trackNumber); actual != expected {
+			t.Errorf("FileExtensionForTrack(%d) = %q, expected %q", trackNumber, actual, expected)
+		}
+	}
+}
+
+func TestNALUnits(t *testing.T) {
+	data := []byte{0, 0, 0, 2, 0x67, 0x42, 0, 0, 0, 1, 0x68, 0, 0, 0, 3, 0x65, 0x88, 0x84}
+
+	nals, err := NALUnits(data)
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	if len(nals) != 3 || len(nals[0]) != 2 || len(nals[1]) != 1 || len(nals[2]) != 3 {
+		t.Errorf("Unexpected NAL split: %v", nals)
+	}
+
+	if _, err := NALUnits(data[:len(data)-1]); err == nil {
+		t.Error("Expected an error for a truncated NAL")
+	}
+}
+
+func TestParseADTSHeader(t *testing.T) {
+	cases := map[string][]byte{
+		"AAC 48kHz stereo": {0xFF, 0xF1, 0x4C, 0x80, 0x00, 0x1F, 0xFC},
+		"AAC 16kHz mono":   {0xFF, 0xF1, 0x60, 0x40, 0x00, 0x1F, 0xFC},
+	}
+
+	for expected, header := range cases {
+		params, err := ParseADTSHeader(header)
+		if err != nil {
+			t.Fatal(err)
+		}
+
+		if actual := params.String(); actual != expected {
+			t.Errorf("ParseADTSHeader gave %q, expected %q", actual, expected)
+		}
+	}
+
+	if _, err := ParseADTSHeader([]byte{0x00, 0x00, 0x4C, 0x80, 0x00, 0x1F, 0xFC}); err == nil {
+		t.Error("Expected an error for a missing syncword")
+	}
+}
+
+func TestClassifyRecording(t *testing.T) {
+	cases := map[string]RecordingKind{
+		"/srv/unifi-protect/video/2020/08/14/FCECDA1F0A63_0_rotating_1597425468956.ubv": RECORDING_FULL_RESOLUTION,
+		"FCECDA1F0A63_2_rotating_1597425468956.ubv":                                     RECORDING_LOW_RESOLUTION_ROTATING,
+		"FCECDA1F0A63_0_timelapse_1597425468956.ubv":                                    RECORDING_TIMELAPSE,
+		"/tmp/2_rotating_dir/recording.ubv":                                             RECORDING_UNKNOWN,
+	}
+
+	for ubvFile, expected := range cases {
+		if actual := ClassifyRecording(ubvFile); actual != expected {
+			t.Errorf("ClassifyRecording(%q) = %s, expected %s", ubvFile, actual, expected)
+		}
+	}
+}
+
+func TestKeyframeBefore(t *testing.T) {
+	start := time.Unix(1600000000, 0)
+	partition := &UbvPartition{
+		Tracks: map[int]*UbvTrack{
+			7: {IsVideo: true, TrackNumber: 7, StartTimecode: start, clockRate: 90000},
+		},
+	}
+
+	// 30fps in 90kHz units with a 5 frame GOP, each video frame followed by an audio packet
+	for i := 0; i < 10; i++ {
+		partition.Frames = append(partition.Frames,
+			UbvFrame{TrackNumber: 7, IsKeyframe: i%5 == 0, Dts: int64(90000 + i*3000)},
+			UbvFrame{TrackNumber: 1000, Dts: int64(i * 1024)})
+	}
+
+	cases := map[int64]int{
+		89999:  -1,
+		90000:  0,
+		104999: 0,
+		105000: 10,
+		200000: 10,
+	}
+
+	for dts, expected := range cases {
+		if actual := partition.KeyframeBeforeDts(dts); actual != expected {
+			t.Errorf("KeyframeBeforeDts(%d) = %d, expected %d", dts, actual, expected)
+		}
+	}
+
+	if actual := partition.KeyframeBefore(start.Add(-time.Second)); actual != -1 {
+		t.Errorf("KeyframeBefore(start - 1s) = %d, expected -1", actual)
+	}
+	if actual := partition.KeyframeBefore(start.Add(200 * time.Millisecond)); actual != 10 {
+		t.Errorf("KeyframeBefore(start + 200ms) = %d, expected 10", actual)
+	}
+	if actual := partition.KeyframeBefore(start.Add(48 * time.Hour)); actual != 10 {
+		t.Errorf("KeyframeBefore(start + 48h) = %d, expected 10", actual)
+	}
+}
+
+func TestGopStatistics(t *testing.T) {
+	track := &UbvTrack{IsVideo: true, TrackNumber: 7}
+
+	// Two leading frames from an earlier GOP, then GOPs of 3, 5 and 1 frames
+	for _, keyframe := range []bool{false, false, true, false, false, true, false, false, false, false, true} {
+		track.countFrame(UbvFrame{TrackNumber: 7, IsKeyframe: keyframe})
+	}
+
+	if track.FrameCount != 11 || track.KeyframeCount != 3 {
+		t.Errorf("Expected 11 frames and 3 keyframes, got %d and %d", track.FrameCount, track.KeyframeCount)
+	}
+
+	if track.MaxGopLength != 5 {
+		t.Errorf("Expected a max GOP length of 5, got %d", track.MaxGopLength)
+	}
+
+	if average := track.AverageGopLength(); average != 3 {
+		t.Errorf("Expected an average GOP length of 3, got %f", average)
+	}
+}
+
+// Builds a ubnt_ubvinfo frame line's fields for a video frame with the given wall-clock (in ms)
+func videoFrameFields(wcMillis int64) []string {
+	return []string{"V", "7", "0", "0", "100", "0", "0", strconv.FormatInt(wcMillis, 10), "1000"}
+}
+
+func TestJitteryLowFramerate(t *testing.T) {
+	track := &UbvTrack{IsVideo: true, TrackNumber: 7}
+
+	// 1-5fps with jitter, including two frames sharing a timecode
+	for _, wc := range []int64{1600000000000, 1600000000000, 1600000000200, 1600000001200, 1600000001500, 1600000002500} {
+		fields := videoFrameFields(wc)
+		extractTimecodeAndRate(fields, strings.Join(fields, " "), track, 0)
+		track.FrameCount++
+	}
+
+	if track.Rate != 0 {
+		t.Errorf("Expected rate to be undetermined for frames with identical timecodes, got %d", track.Rate)
+	}
+
+	if average := track.AverageRate(); average != 2 {
+		t.Errorf("Expected an average rate of 2fps, got %f", average)
+	}
+}
+
+func TestSplitPartition(t *testing.T) {
+	start := time.Unix(1600000000, 0)
+	partition := &UbvPartition{
+		Index:  3,
+		Tracks: map[int]*UbvTrack{
+			7:    {IsVideo: true, TrackNumber: 7, Rate: 5, StartTimecode: start},
+			1000: {TrackNumber: 1000, Rate: 16000, StartTimecode: start},
+		},
+	}
+
+	// 25 frames at 5fps (5 seconds) with a keyframe every 5 frames, each followed by an audio packet
+	for i := 0; i < 25; i++ {
+		partition.Frames = append(partition.Frames, UbvFrame{TrackNumber: 7, IsKeyframe: i%5 == 0}, UbvFrame{TrackNumber: 1000})
+	}
+
+	segments := SplitPartition(partition, 2*time.Second)
+
+	if len(segments) != 3 {
+		t.Fatalf("Expected 3 segments, got %d", len(segments))
+	}
+
+	for i, expected := range []int{10, 10, 5} {
+		if actual := segments[i].Tracks[7].FrameCount; actual != expected {
+			t.Errorf("Segment %d has %d video frames, expected %d", i, actual, expected)
+		}
+		if actual := segments[i].Tracks[1000].FrameCount; actual != expected {
+			t.Errorf("Segment %d has %d audio frames, expected %d", i, actual, expected)
+		}
+		if !segments[i].Frames[0].IsKeyframe {
+			t.Errorf("Segment %d does not start with a keyframe", i)
+		}
+		if expected := fmt.Sprintf("3.%d", i+1); segments[i].Name() != expected || segments[i].Index != 3 {
+			t.Errorf("Segment %d is named %q, expected %q", i, segments[i].Name(), expected)
+		}
+	}
+
+	if unsplit := SplitPartition(partition, time.Hour); len(unsplit) != 1 || unsplit[0].Name() != "3" {
+		t.Errorf("Expected a partition shorter than the segment duration to be left whole and named \"3\"")
+	}
+
+	if actual := segments[1].Tracks[7].StartTimecode; !actual.Equal(start.Add(2 * time.Second)) {
+		t.Errorf("Second segment starts at %s, expected %s", actual, start.Add(2*time.Second))
+	}
+}
+
+func TestJoinPartitions(t *testing.T) {
+	// Two files of one recording (WC in 90kHz units), the second starting 1s after the first with its DTS restarted
+	first := `Type TrackId KeyFrame   Offset     Size        DTS        CTS               WC    TB
+----------- PARTITION START -----------
+   V     7        1      352    48234          0          0 144000000000000 90000
+   V     7        0    48586     4311       3000       3000 144000000003000 90000
+   V     7        0    52897     3982       6000       6000 144000000006000 90000
+`
+	second := `Type TrackId KeyFrame   Offset     Size        DTS        CTS               WC    TB
+----------- PARTITION START -----------
+   V     7        1      352    47120          0          0 144000000090000 90000
+   V     7        0    47472     3874       3000       3000 144000000093000 90000
+`
+
+	var partitions []*UbvPartition
+	for i, sample := range []string{first, second} {
+		info, err := ParseUbvInfo(fmt.Sprintf("FCECDA1F0A63_0_rotating_160000000%d000.ubv", i), strings.NewReader(sample), ParseOptions{})
+		if err != nil {
+			t.Fatal(err)
+		}
+
+		partitions = append(partitions, info.Partitions[0])
+	}
+
+	joined := JoinPartitions(partitions)
+
+	if joined.FrameCount != 5 || len(joined.Frames) != 5 || joined.Tracks[7].FrameCount != 5 || joined.Tracks[7].KeyframeCount != 2 {
+		t.Fatalf("Expected 5 frames (2 keyframes) in the joined partition, got %d", len(joined.Frames))
+	}
+
+	if joined.Index != partitions[0].Index {
+		t.Errorf("Expected the joined partition to take the index of the first, got %d", joined.Index)
+	}
+
+	// The second file's frames carry on 1s of DTS after the first file's start
+	for i, expected := range []int64{0, 3000, 6000, 90000, 93000} {
+		if frame := joined.Frames[i]; frame.Dts != expected || frame.Cts != expected {
+			t.Errorf("Frame %d has DTS %d and CTS %d, expected %d", i, frame.Dts, frame.Cts, expected)
+		}
+	}
+
+	// Rebasing copies the frames, leaving the partitions joined untouched
+	if partitions[1].Frames[0].Dts != 0 {
+		t.Errorf("Expected the second partition's frames to be left as parsed, got DTS %d", partitions[1].Frames[0].Dts)
+	}
+
+	assertTimecode(t, "Joined start", joined.StartTimecode, time.Unix(1600000000, 0))
+	assertTimecode(t, "Joined end", joined.Tracks[7].LastTimecode, time.Unix(1600000001, 0).Add(3000*time.Second/90000))
+}
+
+func TestZeroClockRate(t *testing.T) {
+	track := &UbvTrack{IsVideo: true, TrackNumber: 7}
+
+	// 30fps in 90kHz units, but with a TBC of 0
+	for _, wc := range []int64{1600000000 * 90000, 1600000000*90000 + 3000} {
+		fields := []string{"V", "7", "0", "0", "100", "0", "0", strconv.FormatInt(wc, 10), "0"}
+		extractTimecodeAndRate(fields, strings.Join(fields, " "), track, 0)
+		track.FrameCount++
+	}
+
+	if !track.StartTimecode.Equal(time.Unix(1600000000, 0)) {
+		t.Errorf("Expected a start timecode of %s assuming a 90kHz timebase, got %s", time.Unix(1600000000, 0), track.StartTimecode)
+	}
+
+	if track.Rate != 30 {
+		t.Errorf("Expected a rate of 30fps, got %d", track.Rate)
+	}
+}
+
+// A few lines of ubnt_ubvinfo -P output: a header, then one partition holding a GOP of video and an audio packet
+const UBVINFO_SAMPLE = `Type TrackId KeyFrame   Offset     Size        DTS        CTS            WC    TB
+----------- PARTITION START -----------
+   V     7        1      352    48234  189023400  189023400 1597425469004  1000
+   A  1000        1    48642      371   85077120   85077120 1597425469010  1000
+   V     7        0    49069     4311  189026400  189029400 1597425469037  1000
+   V     7        0    53436     3982  189029400  189032400 1597425469071  1000
+`
+
+func TestParseUbvInfo(t *testing.T) {
+	info, err := ParseUbvInfo("sample.ubv", strings.NewReader(UBVINFO_SAMPLE), ParseOptions{IncludeAudio: true})
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	if len(info.Partitions) != 1 {
+		t.Fatalf("Expected 1 partition, got %d", len(info.Partitions))
+	}
+
+	expected := []UbvFrame{
+		{TrackNumber: 7, IsKeyframe: true, Offset: 352, Size: 48234, Dts: 189023400, Cts: 189023400},
+		{TrackNumber: 1000, IsKeyframe: true, Offset: 48642, Size: 371, Dts: 85077120, Cts: 85077120},
+		{TrackNumber: 7, IsKeyframe: false, Offset: 49069, Size: 4311, Dts: 189026400, Cts: 189029400},
+		{TrackNumber: 7, IsKeyframe: false, Offset: 53436, Size: 3982, Dts: 189029400, Cts: 189032400},
+	}
+
+	frames := info.Partitions[0].Frames
+	if len(frames) != len(expected) {
+		t.Fatalf("Expected %d frames, got %d", len(expected), len(frames))
+	}
+
+	for i, frame := range frames {
+		if frame.TrackNumber != expected[i].TrackNumber || frame.IsKeyframe != expected[i].IsKeyframe || frame.Offset != expected[i].Offset || frame.Size != expected[i].Size {
+			t.Errorf("Frame %d is %+v, expected %+v", i, frame, expected[i])
+		}
+		if frame.Dts != expected[i].Dts || frame.Cts != expected[i].Cts {
+			t.Errorf("Frame %d has DTS %d and CTS %d, expected %d and %d", i, frame.Dts, frame.Cts, expected[i].Dts, expected[i].Cts)
+		}
+	}
+
+	if track := info.Partitions[0].Tracks[7]; track.FrameCount != 3 || track.KeyframeCount != 1 {
+		t.Errorf("Expected 3 video frames with 1 keyframe, got %d with %d", track.FrameCount, track.KeyframeCount)
+	}
+}
+
+func TestParseUbvInfoMalformed(t *testing.T) {
+	cases := map[string]string{
+		"truncated line":     "   V     7        1      352    48234",
+		"non-numeric offset": "   V     7        1      abc    48234  189023400  189023400 1597425469004  1000",
+		"non-numeric DTS":    "   V     7        1      352    48234  -  189023400 1597425469004  1000",
+		"unknown track":      "   V     8        1      352    48234  189023400  189023400 1597425469004  1000",
+		"non-numeric clock":  "   V     7        1      352    48234  189023400  189023400 1597425469004  x",
+		"single field":       "   V",
+		"whitespace only":    "    ",
+	}
+
+	for name, line := range cases {
+		input := "Type TrackId KeyFrame Offset Size DTS CTS WC TB\n----------- PARTITION START -----------\n" + line + "\n"
+
+		if _, err := ParseUbvInfo("sample.ubv", strings.NewReader(input), ParseOptions{}); err == nil {
+			t.Errorf("Expected an error parsing %s", name)
+		}
+	}
+}
+
+// Two partitions: the first begins mid-GOP (two frames before its keyframe), the second with a keyframe
+const UBVINFO_MID_GOP_SAMPLE = `Type TrackId KeyFrame   Offset     Size        DTS        CTS            WC    TB
+----------- PARTITION START -----------
+   V     7        0      352     4311  189020400  189020400 1597425468938  1000
+   V     7        0     4663     3982  189023400  189023400 1597425468971  1000
+   V     7        1     8645    48234  189026400  189026400 1597425469004  1000
+   V     7        0    56879     4102  189029400  189029400 1597425469037  1000
+----------- PARTITION START -----------
+   V     7        1    60981    47120  189032400  189032400 1597425469071  1000
+   V     7        0   108101     3874  189035400  189035400 1597425469104  1000
+`
+
+func TestStartAtKeyframe(t *testing.T) {
+	info, err := ParseUbvInfo("sample.ubv", strings.NewReader(UBVINFO_MID_GOP_SAMPLE), ParseOptions{StartAtKeyframe: true})
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	first := info.Partitions[0]
+	if len(first.Frames) != 2 || first.Tracks[7].FrameCount != 2 {
+		t.Fatalf("Expected the 2 frames preceding the keyframe to be dropped, leaving 2, got %d", len(first.Frames))
+	}
+
+	if !first.Frames[0].IsKeyframe || first.Frames[0].Dts != 189026400 {
+		t.Errorf("Expected the first partition to start at the keyframe, got %+v", first.Frames[0])
+	}
+
+	// The start timecode moves forward to the keyframe's wall-clock
+	keyframeTimecode := time.Unix(1597425469, 4000000)
+	if !first.StartTimecode.Equal(keyframeTimecode) || !first.Tracks[7].StartTimecode.Equal(keyframeTimecode) {
+		t.Errorf("Expected the first partition to start at %s, got %s (track %s)", keyframeTimecode, first.StartTimecode, first.Tracks[7].StartTimecode)
+	}
+
+	if second := info.Partitions[1]; len(second.Frames) != 2 || !second.StartTimecode.Equal(time.Unix(1597425469, 71000000)) {
+		t.Errorf("Expected the second partition to be untouched, got %d frames from %s", len(second.Frames), second.StartTimecode)
+	}
+
+	// Without dropping, every frame is kept and the partition starts at the first frame's wall-clock
+	info, err = ParseUbvInfo("sample.ubv", strings.NewReader(UBVINFO_MID_GOP_SAMPLE), ParseOptions{StartAtKeyframe: false})
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	if first := info.Partitions[0]; len(first.Frames) != 4 || !first.StartTimecode.Equal(time.Unix(1597425468, 938000000)) {
+		t.Errorf("Expected 4 frames from %s, got %d from %s", time.Unix(1597425468, 938000000), len(first.Frames), first.StartTimecode)
+	}
+}
+
+// Checks a timecode is within a millisecond of the expected one (DTS extrapolation isn't exact to the nanosecond)
+func assertTimecode(t *testing.T, name string, actual time.Time, expected time.Time) {
+	if difference := actual.Sub(expected); difference < -time.Millisecond || difference > time.Millisecond {
+		t.Errorf("%s is %s, expected %s", name, actual.Format(time.RFC3339Nano), expected.Format(time.RFC3339Nano))
+	}
+}
+
+func TestUnsyncedPartitions(t *testing.T) {
+	// Two 30fps partitions (90kHz DTS) with no clock sync at all, the second starting 2s after the first
+	sample := `Type TrackId KeyFrame   Offset     Size        DTS        CTS            WC    TB
+----------- PARTITION START -----------
+   V     7        1      352    48234          0          0             0 90000
+   V     7        0    48586     4311       3000       3000             0 90000
+   V     7        0    52897     3982       6000       6000             0 90000
+----------- PARTITION START -----------
+   V     7        1    56879    47120     180000     180000             0 90000
+   V     7        0   103999     3874     183000     183000             0 90000
+`
+
+	info, err := ParseUbvInfo("FCECDA1F0A63_0_rotating_1597425468000.ubv", strings.NewReader(sample), ParseOptions{})
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	fileTimecode := time.Unix(1597425468, 0)
+
+	first := info.Partitions[0].Tracks[7]
+	assertTimecode(t, "First partition start", first.StartTimecode, fileTimecode)
+	assertTimecode(t, "First partition end", first.LastTimecode, fileTimecode.Add(6000*time.Second/90000))
+
+	second := info.Partitions[1].Tracks[7]
+	assertTimecode(t, "Second partition start", second.StartTimecode, fileTimecode.Add(2*time.Second))
+	assertTimecode(t, "Second partition end", second.LastTimecode, fileTimecode.Add(183000*time.Second/90000))
+
+	if first.Rate != 30 || second.Rate != 30 {
+		t.Errorf("Expected a rate of 30fps derived from DTS, got %d and %d", first.Rate, second.Rate)
+	}
+}
+
+func TestTimecodesExtrapolatedFromClockSync(t *testing.T) {
+	// An unsynced partition, then one whose first frame precedes the clock sync (WC in 90kHz units)
+	sample := `Type TrackId KeyFrame   Offset     Size        DTS        CTS               WC    TB
+----------- PARTITION START -----------
+   V     7        1      352    48234          0          0                0 90000
+   V     7        0    48586     4311       3000       3000                0 90000
+----------- PARTITION START -----------
+   V     7        1    52897    47120      90000      90000                0 90000
+   V     7        0   100017     3874      93000      93000 144000000090000 90000
+   V     7        0   103891     3912      96000      96000 144000000093000 90000
+`
+
+	info, err := ParseUbvInfo("FCECDA1F0A63_0_rotating_1597425468000.ubv", strings.NewReader(sample), ParseOptions{})
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	syncTimecode := time.Unix(1600000001, 0)
+
+	// The synced partition is back-dated to its first frame, one frame before the sync
+	synced := info.Partitions[1].Tracks[7]
+	assertTimecode(t, "Synced partition start", synced.StartTimecode, syncTimecode.Add(-3000*time.Second/90000))
+
+	// The rate is measured between the synced frames, not from the back-dated start
+	if synced.Rate != 30 {
+		t.Errorf("Expected a rate of 30fps from the synced frames, got %d", synced.Rate)
+	}
+
+	// The unsynced partition is anchored on the synced one (not the filename), 1s of DTS earlier
+	unsynced := info.Partitions[0].Tracks[7]
+	assertTimecode(t, "Unsynced partition start", unsynced.StartTimecode, syncTimecode.Add(-93000*time.Second/90000))
+}
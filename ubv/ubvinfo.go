@@ -2,12 +2,13 @@ package ubv
 
 import (
 	"bufio"
+	"fmt"
+	"io"
 	"log"
 	"os"
 	"os/exec"
 	"strconv"
 	"strings"
-	"time"
 	"unicode"
 )
 
@@ -18,95 +19,145 @@ const (
 	ubntUbvInfoPath2 = "/usr/share/unifi-protect/app/node_modules/.bin/ubnt_ubvinfo"
 )
 
+// Options controlling how a .ubv is analysed. The zero value analyses video only, keeping every frame
+type ParseOptions struct {
+	// If true, audio is analysed as well as video. N.B. only applies when running ubnt_ubvinfo, a cached .ubv.txt
+	// contains whichever tracks it was generated with
+	IncludeAudio bool
+
+	// If true, video frames preceding the first keyframe of each partition (which can't be decoded) are dropped
+	StartAtKeyframe bool
+
+	// If non-zero, the timebase assumed for video frames whose own timebase is invalid (otherwise
+	// DEFAULT_VIDEO_CLOCK_RATE)
+	VideoClockRate int64
+}
+
 // Analyse a .ubv file (picking between ubnt_ubvinfo or a pre-prepared .txt file as appropriate)
-func Analyse(ubvFile string, includeAudio bool) UbvFile {
+func Analyse(ubvFile string, opts ParseOptions) (UbvFile, error) {
 	cachedUbvInfoFile := ubvFile + ".txt"
 
 	if _, err := os.Stat(cachedUbvInfoFile); err != nil {
 		// No existing analysis, must run ubnt_ubvinfo
-		return runUbvInfo(ubvFile, includeAudio)
+		return runUbvInfo(ubvFile, opts)
 	} else {
 		// Analysis file exists, read that instead of re-running ubnt_ubvinfo
-		return parseUbvInfoFile(ubvFile, cachedUbvInfoFile)
+		return parseUbvInfoFile(ubvFile, cachedUbvInfoFile, opts)
 	}
 }
 
 // Looks for ubnt_ubvinfo on the path and in the default Protect install location
-func getUbvInfoCommand() string {
+func getUbvInfoCommand() (string, error) {
 	paths := [...]string{ubntUbvInfoPath1, ubntUbvInfoPath2}
 
 	for _, path := range paths {
 		if _, err := exec.LookPath(path); err == nil {
-			return path
+			return path, nil
 		}
 	}
 
-	log.Fatal("ubnt_ubvinfo not on PATH, nor in any default search locations!")
-
-	// Keep compiler happy (log.Fatal dies)
-	return paths[0]
+	return "", fmt.Errorf("ubnt_ubvinfo not on PATH, nor in any default search locations")
 }
 
-func runUbvInfo(ubvFile string, includeAudio bool) UbvFile {
-	ubntUbvinfo := getUbvInfoCommand()
+func runUbvInfo(ubvFile string, opts ParseOptions) (UbvFile, error) {
+	ubntUbvinfo, err := getUbvInfoCommand()
+	if err != nil {
+		return UbvFile{}, err
+	}
+
 	cmd := exec.Command(ubntUbvinfo, "-P", "-f", ubvFile)
 
 	// Optimise video-only extraction to speed ubnt_ubvinfo part of process
-	if !includeAudio {
+	if !opts.IncludeAudio {
 		cmd = exec.Command(ubntUbvinfo, "-t", "7", "-P", "-f", ubvFile)
 	}
 
-	// Parse stdout in the background
-	var info UbvFile
-	{
-		cmdReader, err := cmd.StdoutPipe()
-		if err != nil {
-			log.Fatal("Error creating StdoutPipe for Cmd: ", err)
-		}
-
-		scanner := bufio.NewScanner(cmdReader)
-
-		go func() {
-			info = parseUbvInfo(ubvFile, scanner)
-		}()
+	cmdReader, err := cmd.StdoutPipe()
+	if err != nil {
+		return UbvFile{}, fmt.Errorf("error creating StdoutPipe for ubnt_ubvinfo: %v", err)
 	}
 
-	err := cmd.Start()
+	err = cmd.Start()
 	if err != nil {
-		log.Fatal("ubnt_ubvinfo command failed against ", ubvFile, ": ", err)
+		return UbvFile{}, fmt.Errorf("ubnt_ubvinfo command failed against %s: %v", ubvFile, err)
 	}
 
-	// Await the parsed UBV Info
-	for !info.Complete {
-		time.Sleep(100 * time.Millisecond)
+	// If parsing fails part way through, don't leave ubnt_ubvinfo running
+	defer func() {
+		if cmd.ProcessState == nil {
+			cmd.Process.Kill()
+			cmd.Wait()
+		}
+	}()
+
+	// N.B. the output is parsed on the calling goroutine (as it's produced), so the caller can recover from a panic on a
+	// malformed recording
+	info, err := parseUbvInfo(ubvFile, bufio.NewScanner(cmdReader), opts)
+	if err != nil {
+		return UbvFile{}, err
 	}
 
 	// Call wait so stdout/stderr pipes are cleaned up
 	err = cmd.Wait()
 	if err != nil {
-		log.Fatal("Error waiting for ubv: ", err)
+		return UbvFile{}, fmt.Errorf("ubnt_ubvinfo failed against %s: %v", ubvFile, err)
 	}
 
-	return info
+	return info, nil
 }
 
-func parseUbvInfoFile(ubvFile string, ubvInfoFile string) UbvFile {
+func parseUbvInfoFile(ubvFile string, ubvInfoFile string, opts ParseOptions) (UbvFile, error) {
 	f, err := os.Open(ubvInfoFile)
 
 	if err != nil {
-		log.Fatal(err)
+		return UbvFile{}, err
 	}
 
 	defer f.Close()
 
 	scanner := bufio.NewScanner(f)
 
-	return parseUbvInfo(ubvFile, scanner)
+	return parseUbvInfo(ubvFile, scanner, opts)
+}
+
+// Called for each frame as ubnt_ubvinfo output is scanned. The partition and track summaries include this frame (so
+// track.LastTimecode is the frame's wall-clock, if it has one); partition.Frames is only populated by the handler
+type FrameHandler func(partition *UbvPartition, track *UbvTrack, frame UbvFrame)
+
+// Scans ubnt_ubvinfo output (e.g. a cached .ubv.txt), passing each frame to handler rather than accumulating them,
+// so arbitrarily large files can be processed in bounded memory. Returns the partition and track summaries
+func ScanUbvInfo(ubvFile string, r io.Reader, opts ParseOptions, handler FrameHandler) ([]*UbvPartition, error) {
+	return scanUbvInfo(ubvFile, bufio.NewScanner(r), opts, handler)
 }
 
-func parseUbvInfo(ubvFile string, scanner *bufio.Scanner) UbvFile {
+// Parses ubnt_ubvinfo output read from any source (e.g. a cached .ubv.txt fetched over the network), for callers that
+// don't have the analysis on local disk. ubvFile is the name of the .ubv the output describes
+func ParseUbvInfo(ubvFile string, r io.Reader, opts ParseOptions) (UbvFile, error) {
+	return parseUbvInfo(ubvFile, bufio.NewScanner(r), opts)
+}
+
+func parseUbvInfo(ubvFile string, scanner *bufio.Scanner, opts ParseOptions) (UbvFile, error) {
+	partitions, err := scanUbvInfo(ubvFile, scanner, opts, func(partition *UbvPartition, track *UbvTrack, frame UbvFrame) {
+		partition.Frames = append(partition.Frames, frame)
+	})
+	if err != nil {
+		return UbvFile{}, err
+	}
+
+	return UbvFile{
+		Complete:   true,
+		Filename:   ubvFile,
+		Partitions: partitions,
+	}, nil
+}
+
+func scanUbvInfo(ubvFile string, scanner *bufio.Scanner, opts ParseOptions, handler FrameHandler) ([]*UbvPartition, error) {
 	var err error
 
+	// Video frames dropped from the current partition because they precede its first keyframe
+	droppedFrames := 0
+	seenKeyframe := false
+
 	var firstLine bool
 	var partitions []*UbvPartition
 
@@ -124,6 +175,10 @@ func parseUbvInfo(ubvFile string, scanner *bufio.Scanner) UbvFile {
 		if firstLine {
 			firstLine = false
 		} else if line == "----------- PARTITION START -----------" {
+			logDroppedFrames(current, droppedFrames, seenKeyframe)
+			droppedFrames = 0
+			seenKeyframe = false
+
 			// Start a new partition
 			current = &UbvPartition{
 				Index:  len(partitions),
@@ -137,22 +192,45 @@ func parseUbvInfo(ubvFile string, scanner *bufio.Scanner) UbvFile {
 
 			fields := strings.Fields(line)
 
+			if len(fields) <= FIELD_WC_TBC {
+				return nil, fmt.Errorf("expected at least %d fields but found %d in line %q", FIELD_WC_TBC+1, len(fields), line)
+			}
+
 			var frame = UbvFrame{}
 
 			if frame.TrackNumber, err = strconv.Atoi(fields[FIELD_TRACK_ID]); err != nil {
-				log.Fatal("Error parsing track number!", err)
+				return nil, fmt.Errorf("error parsing track number in line %q: %v", line, err)
 			}
 			if frame.Offset, err = strconv.Atoi(fields[FIELD_OFFSET]); err != nil {
-				log.Fatal("Error parsing field offset!", err)
+				return nil, fmt.Errorf("error parsing offset in line %q: %v", line, err)
 			}
 			if frame.Size, err = strconv.Atoi(fields[FIELD_SIZE]); err != nil {
-				log.Fatal("Error parsing frame size!", err)
+				return nil, fmt.Errorf("error parsing frame size in line %q: %v", line, err)
+			}
+			if frame.Dts, err = strconv.ParseInt(fields[FIELD_DTS], 10, 64); err != nil {
+				return nil, fmt.Errorf("error parsing frame DTS in line %q: %v", line, err)
 			}
+			if frame.Cts, err = strconv.ParseInt(fields[FIELD_CTS], 10, 64); err != nil {
+				return nil, fmt.Errorf("error parsing frame CTS in line %q: %v", line, err)
+			}
+
+			frame.IsKeyframe = fields[FIELD_IS_KEYFRAME] == "1"
 
 			// Bail if we encounter an unexpected track number
 			// We could silently ignore it, but it seems more useful to know about new cases
 			if frame.TrackNumber != 7 && frame.TrackNumber != 1000 {
-				log.Fatal("Encountered track number other than 7 or 1000: ", frame.TrackNumber)
+				return nil, fmt.Errorf("encountered track number other than 7 or 1000: %d", frame.TrackNumber)
+			}
+
+			// Optionally drop leading video frames (the rest of a GOP that began in an earlier partition), which
+			// can't be decoded without their keyframe
+			if frame.TrackNumber == 7 && opts.StartAtKeyframe && !seenKeyframe {
+				if !frame.IsKeyframe {
+					droppedFrames++
+					continue
+				}
+
+				seenKeyframe = true
 			}
 
 			track, ok := current.Tracks[frame.TrackNumber]
@@ -174,22 +252,42 @@ func parseUbvInfo(ubvFile string, scanner *bufio.Scanner) UbvFile {
 				}
 			}
 
+			// Track the DTS range, so wall-clock can be extrapolated to frames that precede (or lack) a clock sync
+			if track.FrameCount == 0 {
+				track.firstDts = frame.Dts
+			}
+			track.lastDts = frame.Dts
+
 			// Add Timecode and Rate data to the Track record
-			extractTimecodeAndRate(fields, line, track)
+			if err := extractTimecodeAndRate(fields, line, track, opts.VideoClockRate); err != nil {
+				return nil, err
+			}
 
 			current.FrameCount++
-			track.FrameCount++
-			current.Frames = append(current.Frames, frame)
+			track.countFrame(frame)
+			handler(current, track, frame)
 		}
 	}
 
 	if err := scanner.Err(); err != nil {
-		log.Fatal("error reading ubv", ubvFile, err)
+		return nil, fmt.Errorf("error reading analysis of %s: %v", ubvFile, err)
 	}
 
-	return UbvFile{
-		Complete:   true,
-		Filename:   ubvFile,
-		Partitions: partitions,
+	logDroppedFrames(current, droppedFrames, seenKeyframe)
+
+	fillMissingTimecodes(ubvFile, partitions)
+
+	for _, partition := range partitions {
+		setPartitionTimecodes(partition)
+	}
+
+	return partitions, nil
+}
+
+func logDroppedFrames(partition *UbvPartition, droppedFrames int, seenKeyframe bool) {
+	if droppedFrames > 0 && !seenKeyframe {
+		log.Printf("Warning: partition %d has no video keyframes, so all %d video frames were dropped. Try -start-at-keyframe=false (and please report the file)", partition.Index, droppedFrames)
+	} else if droppedFrames > 0 {
+		log.Printf("Dropped %d video frame(s) preceding the first keyframe of partition %d", droppedFrames, partition.Index)
 	}
 }
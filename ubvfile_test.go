@@ -1,7 +1,15 @@
 package main
 
 import (
+	"bytes"
+	"crypto/sha256"
+	"fmt"
+	"io/ioutil"
 	"log"
+	"os"
+	"os/exec"
+	"path/filepath"
+	"strings"
 	"testing"
 	"time"
 	"ubvremux/ubv"
@@ -10,7 +18,10 @@ import (
 func TestCopyFrames(t *testing.T) {
 	ubvFile := "samples/FCECDA1F0A63_0_rotating_1597425468956.ubv"
 
-	info := ubv.Analyse(ubvFile, true)
+	info, err := ubv.Analyse(ubvFile, ubv.ParseOptions{IncludeAudio: true})
+	if err != nil {
+		t.Fatal("Analysis failed: ", err)
+	}
 
 	log.Printf("\n\n*** Parsing complete! ***\n\n")
 	log.Printf("Number of partitions: %d", len(info.Partitions))
@@ -27,3 +38,236 @@ func TestCopyFrames(t *testing.T) {
 
 	t.Log("Analysis completed")
 }
+
+// Remuxes every sample .ubv to MP4 and checks the result with FFprobe (streams, dimensions and duration). Skipped
+// unless samples are present along with FFmpeg, FFprobe and either ubnt_ubvinfo or a cached .ubv.txt
+func TestRemuxSamples(t *testing.T) {
+	samples, _ := filepath.Glob("samples/*.ubv")
+	if len(samples) == 0 {
+		t.Skip("No sample .ubv files")
+	}
+
+	for _, command := range []string{"ffmpeg", "ffprobe"} {
+		if _, err := exec.LookPath(command); err != nil {
+			t.Skip(command, " not on PATH")
+		}
+	}
+
+	for _, ubvFile := range samples {
+		t.Run(filepath.Base(ubvFile), func(t *testing.T) {
+			if _, err := os.Stat(ubvFile + ".txt"); err != nil {
+				if _, err := exec.LookPath("ubnt_ubvinfo"); err != nil {
+					t.Skip("No cached .ubv.txt and ubnt_ubvinfo not on PATH")
+				}
+			}
+
+			reports := RemuxCLI([]string{ubvFile}, RemuxConfig{
+				ExtractAudio:    true,
+				ExtractVideo:    true,
+				MaxFps:          60,
+				StartAtKeyframe: true,
+				PartitionIndex:  -1,
+				CreateMP4:       true,
+				OutputFolder:    t.TempDir(),
+				Verify:          true,
+			})
+
+			if len(reports) != 1 {
+				t.Fatalf("Expected 1 report, got %d", len(reports))
+			} else if !reports[0].Success {
+				t.Errorf("Remux failed: %v", reports[0].Errors)
+			} else if len(reports[0].Outputs) == 0 {
+				t.Error("No MP4s were written")
+			}
+		})
+	}
+}
+
+func TestOutputBasename(t *testing.T) {
+	timecode := time.Date(2020, 8, 14, 17, 17, 48, 0, time.UTC)
+
+	timezone, err := parseTimezone("+02:00")
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	config := RemuxConfig{OutputFolder: "/out", Timezone: timezone}
+
+	if name, err := outputBasename("/in/FCECDA1F0A63_0_rotating_1597425468956.ubv", timecode, config); err != nil || name != "/out/FCECDA1F0A63_0_rotating_2020-08-14T19.17.48+02.00" {
+		t.Errorf("Unexpected basename %s (%v)", name, err)
+	}
+
+	config.TimeFormat = "20060102_150405"
+
+	if name, err := outputBasename("/in/FCECDA1F0A63_0_rotating_1597425468956.ubv", timecode, config); err != nil || name != "/out/FCECDA1F0A63_0_rotating_20200814_191748" {
+		t.Errorf("Unexpected basename %s (%v)", name, err)
+	}
+
+	// The zone abbreviation of a fixed offset is the offset itself, so this layout only gives a : in some timezones
+	config.TimeFormat = "20060102_150405_MST"
+
+	if _, err := outputBasename("/in/FCECDA1F0A63_0_rotating_1597425468956.ubv", timecode, config); err == nil {
+		t.Error("Expected a timecode containing : to be rejected")
+	}
+
+	config.Timezone = time.UTC
+
+	if name, err := outputBasename("/in/FCECDA1F0A63_0_rotating_1597425468956.ubv", timecode, config); err != nil || name != "/out/FCECDA1F0A63_0_rotating_20200814_171748_UTC" {
+		t.Errorf("Unexpected basename %s (%v)", name, err)
+	}
+
+	if _, err := parseTimezone("+2h"); err == nil {
+		t.Error("Expected an invalid offset to be rejected")
+	}
+}
+
+func TestDuplicateOutputs(t *testing.T) {
+	config := RemuxConfig{OutputFolder: "/out", Timezone: time.UTC, TimeFormat: "20060102_1504"}
+	claimed := make(map[string]string)
+
+	// Partitions starting within the same minute get the same basename with a per-minute format
+	for i, second := range []int{5, 40} {
+		basename, err := outputBasename("/in/FCECDA1F0A63_0_rotating_1597425468956.ubv", time.Date(2020, 8, 14, 17, 17, second, 0, time.UTC), config)
+		if err != nil {
+			t.Fatal(err)
+		}
+
+		err = claimOutput(claimed, basename, fmt.Sprintf("partition %d", i))
+		if i == 0 && err != nil {
+			t.Errorf("Expected the first partition's output to be claimed: %v", err)
+		} else if i == 1 && err == nil {
+			t.Error("Expected a second partition with the same basename to be rejected")
+		}
+	}
+}
+
+func TestFilenameCharacters(t *testing.T) {
+	for _, name := range []string{"front:door", "what?", "a/b", "a\\b", "<cam>", "tab\there"} {
+		if err := checkFilename(name); err == nil {
+			t.Errorf("Expected %q to be rejected", name)
+		}
+	}
+
+	for _, name := range []string{"", "FCECDA1F0A63_0_rotating", "2020-08-14T17.17.48Z", "front door (1)"} {
+		if err := checkFilename(name); err != nil {
+			t.Errorf("Expected %q to be allowed: %v", name, err)
+		}
+	}
+
+	if name := sanitiseFilename("cam:1?"); name != "cam_1_" {
+		t.Errorf("Unexpected sanitised name %s", name)
+	}
+}
+
+func TestCorrectVideoRate(t *testing.T) {
+	// 3fps by DTS (90kHz), but the first two wall-clocks are only 33ms apart so the detected rate is 30fps
+	jittery := `Type TrackId KeyFrame   Offset     Size        DTS        CTS               WC    TB
+----------- PARTITION START -----------
+   V     7        1      352    48234          0          0  144000000000000 90000
+   V     7        0    48586     4311      30000      30000  144000000002970 90000
+   V     7        0    52897     3982      60000      60000  144000000060000 90000
+   V     7        0    56879     4102      90000      90000  144000000090000 90000
+`
+
+	// 30fps by both DTS and wall-clock
+	steady := `Type TrackId KeyFrame   Offset     Size        DTS        CTS               WC    TB
+----------- PARTITION START -----------
+   V     7        1      352    48234          0          0  144000000000000 90000
+   V     7        0    48586     4311       3000       3000  144000000003000 90000
+   V     7        0    52897     3982       6000       6000  144000000006000 90000
+`
+
+	cases := []struct {
+		name     string
+		sample   string
+		maxFps   int
+		expected int
+	}{
+		{"jittery first frames", jittery, 60, 3},
+		{"steady", steady, 60, 30},
+		{"steady above max-fps", steady, 25, 25},
+	}
+
+	for _, c := range cases {
+		info, err := ubv.ParseUbvInfo("sample.ubv", strings.NewReader(c.sample), ubv.ParseOptions{})
+		if err != nil {
+			t.Fatal(err)
+		}
+
+		partition := info.Partitions[0]
+		correctVideoRate(partition, partition.Tracks[7], c.maxFps)
+
+		if actual := partition.Tracks[7].Rate; actual != c.expected {
+			t.Errorf("%s: expected a rate of %d fps, got %d", c.name, c.expected, actual)
+		}
+	}
+}
+
+func TestFileDigestIgnoresOptions(t *testing.T) {
+	dir, err := ioutil.TempDir("", "ubvremux")
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	defer os.RemoveAll(dir)
+
+	// The first partition begins mid-GOP and has audio; the frames are laid out back to back
+	ubvFile := filepath.Join(dir, "FCECDA1F0A63_0_rotating_1597425468000.ubv")
+	analysis := `Type TrackId KeyFrame   Offset     Size        DTS        CTS            WC    TB
+----------- PARTITION START -----------
+   V     7        0        0       10          0          0             0 90000
+   V     7        1       10       10       3000       3000             0 90000
+   A  1000        1       20        5          0          0             0 16000
+----------- PARTITION START -----------
+   V     7        1       25       10      90000      90000             0 90000
+`
+
+	essence := []byte("0123456789abcdefghijKLMNOpqrstuvwxy")
+
+	if err := ioutil.WriteFile(ubvFile, essence, 0644); err != nil {
+		t.Fatal(err)
+	}
+	if err := ioutil.WriteFile(ubvFile+".txt", []byte(analysis), 0644); err != nil {
+		t.Fatal(err)
+	}
+
+	expected := sha256.Sum256(essence)
+
+	for _, config := range []RemuxConfig{
+		{ExtractAudio: true, StartAtKeyframe: false},
+		{ExtractAudio: true, StartAtKeyframe: true},
+		{ExtractAudio: false, StartAtKeyframe: false},
+		{ExtractAudio: false, StartAtKeyframe: true, PartitionIndex: 1},
+	} {
+		info, err := ubv.Analyse(ubvFile, ubv.ParseOptions{IncludeAudio: config.ExtractAudio, StartAtKeyframe: config.StartAtKeyframe})
+		if err != nil {
+			t.Fatal(err)
+		}
+
+		digest, err := digestFile(ubvFile, info, config)
+		if err != nil {
+			t.Fatal(err)
+		}
+
+		if !bytes.Equal(digest, expected[:]) {
+			t.Errorf("Digest with audio=%t start-at-keyframe=%t partition=%d is %x, expected %x", config.ExtractAudio, config.StartAtKeyframe, config.PartitionIndex, digest, expected)
+		}
+	}
+
+	// Only the first partition has audio
+	info, err := ubv.Analyse(ubvFile, ubv.ParseOptions{IncludeAudio: true})
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	f, err := os.Open(ubvFile)
+	if err != nil {
+		t.Fatal(err)
+	}
+
+	defer f.Close()
+
+	if digest, err := ubv.DigestPartition(f, info.Partitions[1]); err != nil || digest.Video == nil || digest.Audio != nil {
+		t.Errorf("Expected a video digest but no audio digest for a partition without audio, got %+v (%v)", digest, err)
+	}
+}